@@ -0,0 +1,262 @@
+use crate::common::Entry;
+use crate::output::quote_display_name;
+use std::collections::HashSet;
+use std::io::Write;
+use std::path::Path;
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum VCardVersion {
+    V3,
+    V4,
+}
+
+/// A display name split into given and family name.
+#[derive(PartialEq, Debug)]
+pub struct SplitName<'a> {
+    pub given: &'a str,
+    pub family: &'a str,
+}
+
+fn is_name_word(word: &str) -> bool {
+    let mut chars = word.chars();
+    match chars.next() {
+        Some(c) if c.is_uppercase() => {}
+        _ => return false,
+    }
+    chars.all(|c| c.is_alphabetic() || c == '-' || c == '\'')
+}
+
+/// Split a display name into given and family name, but only if that is unambiguous, i.e., for
+/// names of the form "Given Family" and "Family, Given". Everything else (single words, middle
+/// names, particles like "van", titles, ...) is left alone.
+pub fn split_name(name: &str) -> Option<SplitName<'_>> {
+    let name = name.trim();
+    let mut comma_parts = name.split(',');
+    let (given, family) = match (comma_parts.next(), comma_parts.next(), comma_parts.next()) {
+        (Some(family), Some(given), None) => (given.trim(), family.trim()),
+        (Some(_), None, None) => {
+            let mut words = name.split_whitespace();
+            match (words.next(), words.next(), words.next()) {
+                (Some(given), Some(family), None) => (given, family),
+                _ => return None,
+            }
+        }
+        _ => return None,
+    };
+    if is_name_word(given) && is_name_word(family) {
+        Some(SplitName { given, family })
+    } else {
+        None
+    }
+}
+
+/// Stable identifier for an address (64 bit FNV-1a), used for vCard UIDs and vdir file names.
+fn uid(addr: &str) -> String {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for b in addr.bytes() {
+        hash ^= b as u64;
+        hash = hash.wrapping_mul(0x0000_0100_0000_01b3);
+    }
+    format!("maq-{:016x}", hash)
+}
+
+fn escape_vcard(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '\\' => escaped.push_str("\\\\"),
+            ',' => escaped.push_str("\\,"),
+            ';' => escaped.push_str("\\;"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => {}
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Write a content line, folded after 75 octets as required by RFC 6350/2426.
+fn write_vcard_line(out: &mut impl Write, line: &str) -> std::io::Result<()> {
+    const MAX_LINE_LEN: usize = 75;
+    let mut rest = line;
+    let mut max_len = MAX_LINE_LEN;
+    while rest.len() > max_len {
+        let mut split = max_len;
+        while !rest.is_char_boundary(split) {
+            split -= 1;
+        }
+        write!(out, "{}\r\n ", &rest[..split])?;
+        rest = &rest[split..];
+        // Account for the leading space of the continuation line.
+        max_len = MAX_LINE_LEN - 1;
+    }
+    write!(out, "{}\r\n", rest)
+}
+
+pub fn write_vcard(
+    out: &mut impl Write,
+    entry: &Entry,
+    version: VCardVersion,
+) -> std::io::Result<()> {
+    let addr = entry.addr;
+    let name = entry.preferred_name().map(str::trim).unwrap_or("");
+    let full_name = if name.is_empty() { addr } else { name };
+
+    write_vcard_line(out, "BEGIN:VCARD")?;
+    match version {
+        VCardVersion::V3 => write_vcard_line(out, "VERSION:3.0")?,
+        VCardVersion::V4 => write_vcard_line(out, "VERSION:4.0")?,
+    }
+    write_vcard_line(out, &format!("UID:{}", uid(addr)))?;
+    write_vcard_line(out, &format!("FN:{}", escape_vcard(full_name)))?;
+    let n = match split_name(name) {
+        Some(split) => format!(
+            "N:{};{};;;",
+            escape_vcard(split.family),
+            escape_vcard(split.given)
+        ),
+        None => "N:;;;;".to_owned(),
+    };
+    write_vcard_line(out, &n)?;
+    match version {
+        VCardVersion::V3 => write_vcard_line(out, &format!("EMAIL;TYPE=INTERNET:{}", addr))?,
+        VCardVersion::V4 => write_vcard_line(out, &format!("EMAIL:{}", addr))?,
+    }
+    write_vcard_line(out, "END:VCARD")
+}
+
+/// Write one .vcf file per address into `dir`, as expected by vdirsyncer and khard. Existing
+/// files for the same address are overwritten.
+pub fn write_vdir<'a>(
+    dir: &Path,
+    entries: impl IntoIterator<Item = Entry<'a>>,
+    version: VCardVersion,
+) -> std::io::Result<()> {
+    std::fs::create_dir_all(dir)?;
+    for entry in entries {
+        let path = dir.join(format!("{}.vcf", uid(entry.addr)));
+        let mut file = std::io::BufWriter::new(std::fs::File::create(path)?);
+        write_vcard(&mut file, &entry, version)?;
+        file.flush()?;
+    }
+    Ok(())
+}
+
+fn alias_key(addr: &str, name: Option<&str>) -> String {
+    let base = match name.and_then(split_name) {
+        Some(split) => format!("{}-{}", split.given, split.family),
+        None => addr.split('@').next().unwrap_or(addr).to_owned(),
+    };
+    let key = base
+        .chars()
+        .filter_map(|c| {
+            if c.is_alphanumeric() || c == '.' || c == '_' || c == '-' {
+                Some(c.to_lowercase().next().unwrap_or(c))
+            } else if c.is_whitespace() {
+                Some('-')
+            } else {
+                None
+            }
+        })
+        .collect::<String>();
+    if key.is_empty() {
+        "alias".to_owned()
+    } else {
+        key
+    }
+}
+
+/// Escape `s` for a muttrc command. Mutt runs commands in backticks and expands variables even
+/// within quotes, so a name from a mail header must not reach it unescaped: every character with
+/// a special meaning is escaped with a backslash (which mutt removes again).
+fn escape_muttrc(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        if "`$\\\"'#;".contains(c) {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+/// Writes `alias` lines for mutt/neomutt. Keys are made unique by appending a counter.
+pub struct MuttAliasWriter {
+    used_keys: HashSet<String>,
+}
+
+impl MuttAliasWriter {
+    pub fn new() -> Self {
+        MuttAliasWriter {
+            used_keys: HashSet::new(),
+        }
+    }
+
+    pub fn write(&mut self, out: &mut impl Write, entry: &Entry) -> std::io::Result<()> {
+        let addr = entry.addr;
+        let name = entry.preferred_name();
+        let base = alias_key(addr, name);
+        let mut key = base.clone();
+        let mut i = 2;
+        while !self.used_keys.insert(key.clone()) {
+            key = format!("{}-{}", base, i);
+            i += 1;
+        }
+        // The key only consists of harmless characters (see `alias_key`), but the name and the
+        // address come straight from the mails.
+        let mailbox = match name {
+            Some(name) => format!(
+                "{} <{}>",
+                quote_display_name(&name.replace('\n', " ")),
+                addr
+            ),
+            None => format!("<{}>", addr),
+        };
+        writeln!(out, "alias {} {}", key, escape_muttrc(&mailbox))
+    }
+}
+
+pub fn write_abook_header(out: &mut impl Write) -> std::io::Result<()> {
+    writeln!(out, "# abook addressbook file")?;
+    writeln!(out)?;
+    writeln!(out, "[format]")?;
+    writeln!(out, "program=abook")?;
+    writeln!(out, "version=0.6.1")?;
+    writeln!(out)
+}
+
+pub fn write_abook_entry(out: &mut impl Write, index: usize, entry: &Entry) -> std::io::Result<()> {
+    let addr = entry.addr;
+    let name = entry
+        .preferred_name()
+        .map(|n| n.replace(&['\n', '\r'][..], " "))
+        .unwrap_or_else(|| addr.to_owned());
+    writeln!(out, "[{}]", index)?;
+    writeln!(out, "name={}", name)?;
+    writeln!(out, "email={}", addr)?;
+    writeln!(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::{AddrCollection, AddrData};
+
+    #[test]
+    fn mutt_aliases_escape_special_characters() {
+        let mut collection = AddrCollection::new();
+        let name = "Evil`id` $HOME \\ \" ' # ;";
+        collection.add_data("evil`id`$x@x.org", vec![(name, 1)], AddrData::default());
+        let mut out = Vec::new();
+        let entry = collection.get("evil`id`$x@x.org").unwrap();
+        MuttAliasWriter::new().write(&mut out, &entry).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            concat!(
+                r#"alias evilidx \"Evil\`id\` \$HOME \\\\ \\\" \' \# \;\" "#,
+                r#"<evil\`id\`\$x@x.org>"#,
+                "\n"
+            )
+        );
+    }
+}
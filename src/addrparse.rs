@@ -0,0 +1,581 @@
+//! Parser for the value of address list headers (From, To, CC and BCC).
+//!
+//! It produces exactly the addresses that `mailparse::addrparse_header` produces, quirks included
+//! (see the differential test below), but works on the raw bytes of the header. Addresses and
+//! display names refer to the header wherever possible, so nothing is allocated for the (many)
+//! addresses that do not match. Values with RFC 2047 encoded words are not supported, they have to
+//! be parsed by mailparse.
+
+use bstr::ByteSlice;
+use std::borrow::Cow;
+
+/// Whether the (latin1 decoded) byte is whitespace, see `char::is_whitespace`.
+fn is_whitespace(b: u8) -> bool {
+    matches!(b, b'\t' | b'\n' | 0x0b | 0x0c | b'\r' | b' ' | 0x85 | 0xa0)
+}
+
+fn latin1(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| *b as char).collect()
+}
+
+/// A character of the header after unfolding: Either a byte of the value or the space that
+/// replaces a line break (and the whitespace around it).
+#[derive(Copy, Clone)]
+enum Item {
+    Byte(usize),
+    Space,
+}
+
+/// The characters of a header value as mailparse sees them: Every line is trimmed at the start and
+/// lines are joined by a single space.
+struct Items<'a> {
+    value: &'a [u8],
+    pos: usize,
+    line_end: usize,
+    next_line: Option<usize>,
+}
+
+impl<'a> Items<'a> {
+    fn new(value: &'a [u8]) -> Self {
+        let mut items = Items {
+            value,
+            pos: 0,
+            line_end: 0,
+            next_line: None,
+        };
+        items.start_line(0);
+        items
+    }
+
+    fn start_line(&mut self, start: usize) {
+        let (end, next_line) = match memchr::memchr(b'\n', &self.value[start..]) {
+            Some(offset) => {
+                let newline = start + offset;
+                let end = if newline > start && self.value[newline - 1] == b'\r' {
+                    newline - 1
+                } else {
+                    newline
+                };
+                // There is no (empty) line after a trailing line break.
+                let next_line = Some(newline + 1).filter(|n| *n < self.value.len());
+                (end, next_line)
+            }
+            None => (self.value.len(), None),
+        };
+        let mut pos = start;
+        while pos < end && is_whitespace(self.value[pos]) {
+            pos += 1;
+        }
+        self.pos = pos;
+        self.line_end = end;
+        self.next_line = next_line;
+    }
+}
+
+impl Iterator for Items<'_> {
+    type Item = Item;
+
+    fn next(&mut self) -> Option<Item> {
+        if self.pos < self.line_end {
+            self.pos += 1;
+            Some(Item::Byte(self.pos - 1))
+        } else {
+            let next_line = self.next_line?;
+            self.start_line(next_line);
+            Some(Item::Space)
+        }
+    }
+}
+
+/// An address or display name. Stays a range of the header value as long as it is contiguous
+/// there.
+#[derive(Clone, Debug)]
+pub enum Text {
+    Span(usize, usize),
+    Owned(String),
+}
+
+impl Text {
+    fn new() -> Self {
+        Text::Span(0, 0)
+    }
+
+    fn push(&mut self, value: &[u8], item: Item) {
+        match (&mut *self, item) {
+            (Text::Span(start, end), Item::Byte(i)) if start == end => {
+                *self = Text::Span(i, i + 1);
+            }
+            (Text::Span(_, end), Item::Byte(i)) if *end == i => *end += 1,
+            (Text::Span(start, end), item) => {
+                let mut owned = latin1(&value[*start..*end]);
+                push_char(&mut owned, value, item);
+                *self = Text::Owned(owned);
+            }
+            (Text::Owned(owned), item) => push_char(owned, value, item),
+        }
+    }
+
+    fn append(&mut self, value: &[u8], other: Text) {
+        match (&mut *self, other) {
+            (Text::Span(start, end), other) if start == end => *self = other,
+            (_, Text::Span(start, end)) if start == end => {}
+            (Text::Span(_, end), Text::Span(other_start, other_end)) if *end == other_start => {
+                *end = other_end
+            }
+            (this, other) => {
+                let mut owned = match this {
+                    Text::Span(start, end) => latin1(&value[*start..*end]),
+                    Text::Owned(owned) => std::mem::take(owned),
+                };
+                owned.push_str(&other.resolve(value));
+                *self = Text::Owned(owned);
+            }
+        }
+    }
+
+    fn trim_end(mut self, value: &[u8]) -> Self {
+        match &mut self {
+            Text::Span(start, end) => {
+                while *end > *start && is_whitespace(value[*end - 1]) {
+                    *end -= 1;
+                }
+            }
+            Text::Owned(owned) => owned.truncate(owned.trim_end().len()),
+        }
+        self
+    }
+
+    fn contains(&self, value: &[u8], b: u8) -> bool {
+        match self {
+            Text::Span(start, end) => memchr::memchr(b, &value[*start..*end]).is_some(),
+            Text::Owned(owned) => memchr::memchr(b, owned.as_bytes()).is_some(),
+        }
+    }
+
+    /// The text itself, given the header value that was parsed.
+    pub fn resolve<'a>(&'a self, value: &'a [u8]) -> Cow<'a, str> {
+        match self {
+            Text::Span(start, end) => {
+                let bytes = &value[*start..*end];
+                if bytes.is_ascii() {
+                    // Safety: ASCII is valid UTF-8.
+                    Cow::Borrowed(unsafe { std::str::from_utf8_unchecked(bytes) })
+                } else {
+                    Cow::Owned(latin1(bytes))
+                }
+            }
+            Text::Owned(owned) => Cow::Borrowed(owned),
+        }
+    }
+}
+
+fn push_char(owned: &mut String, value: &[u8], item: Item) {
+    match item {
+        Item::Byte(i) => owned.push(value[i] as char),
+        Item::Space => owned.push(' '),
+    }
+}
+
+/// An address that is not part of a group (see `parse_address_list`).
+#[derive(Debug)]
+pub struct Address {
+    pub addr: Text,
+    pub display_name: Option<Text>,
+}
+
+/// Why a header value could not be parsed.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Error {
+    /// Contains RFC 2047 encoded words, use mailparse instead.
+    EncodedWords,
+    /// Not a valid address list (as far as mailparse is concerned).
+    Invalid,
+}
+
+enum State {
+    Initial,
+    QuotedName,
+    EscapedChar,
+    AfterQuotedName,
+    BracketedAddr,
+    AfterBracketedAddr,
+    Unquoted,
+    Comment,
+}
+
+/// The value of an address header, i.e., the part after the colon, in the same way as
+/// `mailparse::parse_header` extracts it from `line` (which includes the line break at its end).
+pub fn header_value(line: &[u8]) -> &[u8] {
+    let mut start = match memchr::memchr(b':', line) {
+        Some(colon) => colon + 1,
+        None => return &[],
+    };
+    while line.get(start) == Some(&b' ') {
+        start += 1;
+    }
+    let mut end = line.len();
+    while end > start && line[end - 1] == b'\n' {
+        end -= 1;
+    }
+    &line[start..end]
+}
+
+/// Parse the addresses in `value` (see `header_value`) into `addrs`. If parsing fails, `addrs` may
+/// contain some of the addresses anyway, but none of them should be used.
+///
+/// Addresses that are part of a group are skipped, like maq always did with mailparse.
+pub fn parse_address_list(value: &[u8], addrs: &mut Vec<Address>) -> Result<(), Error> {
+    if value.find(b"=?").is_some() {
+        return Err(Error::EncodedWords);
+    }
+    Parser {
+        value,
+        items: Items::new(value),
+    }
+    .parse(false, addrs)
+}
+
+struct Parser<'a> {
+    value: &'a [u8],
+    items: Items<'a>,
+}
+
+impl Parser<'_> {
+    fn single(
+        &self,
+        display_name: Option<Text>,
+        addr: Text,
+        in_group: bool,
+        addrs: &mut Vec<Address>,
+    ) -> Result<(), Error> {
+        if !addr.contains(self.value, b'@') {
+            return Err(Error::Invalid);
+        }
+        if !in_group {
+            addrs.push(Address { addr, display_name });
+        }
+        Ok(())
+    }
+
+    fn parse(&mut self, in_group: bool, addrs: &mut Vec<Address>) -> Result<(), Error> {
+        let value = self.value;
+        let mut state = State::Initial;
+        let mut name: Option<Text> = None;
+        let mut addr = Text::new();
+        let mut post_quote_ws: Option<Text> = None;
+        let mut comment_return = State::Initial;
+
+        // Nothing at all is fine, even for an unterminated group.
+        let mut item = match self.items.next() {
+            Some(item) => item,
+            None => return Ok(()),
+        };
+        loop {
+            let c = match item {
+                Item::Byte(i) => value[i],
+                Item::Space => b' ',
+            };
+            match state {
+                State::Initial => {
+                    if is_whitespace(c) {
+                    } else if c == b'"' {
+                        state = State::QuotedName;
+                        name = Some(Text::new());
+                    } else if c == b'<' {
+                        state = State::BracketedAddr;
+                        addr = Text::new();
+                    } else if c == b';' {
+                        return if in_group {
+                            Ok(())
+                        } else {
+                            Err(Error::Invalid)
+                        };
+                    } else {
+                        state = State::Unquoted;
+                        addr = Text::new();
+                        addr.push(value, item);
+                    }
+                }
+                State::QuotedName => {
+                    if c == b'\\' {
+                        state = State::EscapedChar;
+                    } else if c == b'"' {
+                        state = State::AfterQuotedName;
+                    } else {
+                        name.as_mut().unwrap().push(value, item);
+                    }
+                }
+                State::EscapedChar => {
+                    state = State::QuotedName;
+                    name.as_mut().unwrap().push(value, item);
+                }
+                State::AfterQuotedName => {
+                    if is_whitespace(c) {
+                        post_quote_ws
+                            .get_or_insert_with(Text::new)
+                            .push(value, item);
+                    } else if c == b'<' {
+                        // Whitespace after the name is kept for the next quoted name (if any).
+                        state = State::BracketedAddr;
+                        addr = Text::new();
+                    } else if c == b':' {
+                        if in_group {
+                            return Err(Error::Invalid);
+                        }
+                        self.parse(true, addrs)?;
+                        state = State::Initial;
+                        name = None;
+                    } else {
+                        let name = name.as_mut().unwrap();
+                        if let Some(ws) = post_quote_ws.take() {
+                            name.append(value, ws);
+                        }
+                        if c == b'"' {
+                            state = State::QuotedName;
+                        } else {
+                            name.push(value, item);
+                        }
+                    }
+                }
+                State::BracketedAddr => {
+                    if c == b'>' {
+                        state = State::AfterBracketedAddr;
+                        let addr = std::mem::replace(&mut addr, Text::new());
+                        self.single(name.take(), addr, in_group, addrs)?;
+                    } else {
+                        addr.push(value, item);
+                    }
+                }
+                State::AfterBracketedAddr => {
+                    if is_whitespace(c) {
+                    } else if c == b',' {
+                        state = State::Initial;
+                    } else if c == b';' {
+                        if in_group {
+                            return Ok(());
+                        }
+                        state = State::Initial;
+                    } else if c == b'(' {
+                        comment_return = State::AfterBracketedAddr;
+                        state = State::Comment;
+                    } else {
+                        return Err(Error::Invalid);
+                    }
+                }
+                State::Unquoted => {
+                    if c == b'<' {
+                        state = State::BracketedAddr;
+                        let unquoted = std::mem::replace(&mut addr, Text::new());
+                        name = Some(unquoted.trim_end(value));
+                    } else if c == b',' || c == b';' {
+                        let unquoted = std::mem::replace(&mut addr, Text::new());
+                        self.single(None, unquoted.trim_end(value), in_group, addrs)?;
+                        if c == b';' && in_group {
+                            return Ok(());
+                        }
+                        state = State::Initial;
+                    } else if c == b':' {
+                        if in_group {
+                            return Err(Error::Invalid);
+                        }
+                        self.parse(true, addrs)?;
+                        state = State::Initial;
+                    } else if c == b'(' {
+                        comment_return = State::Unquoted;
+                        state = State::Comment;
+                    } else {
+                        addr.push(value, item);
+                    }
+                }
+                State::Comment => {
+                    if c == b')' {
+                        state = std::mem::replace(&mut comment_return, State::Initial);
+                    }
+                }
+            }
+            item = match self.items.next() {
+                Some(item) => item,
+                None => break,
+            };
+        }
+
+        if in_group {
+            return Err(Error::Invalid);
+        }
+        match state {
+            State::Initial | State::AfterBracketedAddr => Ok(()),
+            State::Unquoted => self.single(None, addr.trim_end(value), in_group, addrs),
+            _ => Err(Error::Invalid),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::may_contain;
+    use crate::Literal;
+    use mailparse::{addrparse_header, parse_header, MailAddr};
+
+    type Parsed = Option<Vec<(String, Option<String>)>>;
+
+    fn with_mailparse(line: &[u8]) -> Parsed {
+        let (header, _) = parse_header(line).ok()?;
+        let addrs = addrparse_header(&header).ok()?;
+        Some(
+            addrs
+                .into_inner()
+                .into_iter()
+                .filter_map(|addr| match addr {
+                    MailAddr::Single(addr) => Some((addr.addr, addr.display_name)),
+                    MailAddr::Group(_) => None,
+                })
+                .collect(),
+        )
+    }
+
+    /// `None` if the header contains encoded words.
+    fn with_addrparse(line: &[u8]) -> Option<Parsed> {
+        let value = header_value(line);
+        let mut addrs = Vec::new();
+        match parse_address_list(value, &mut addrs) {
+            Ok(()) => Some(Some(
+                addrs
+                    .iter()
+                    .map(|addr| {
+                        (
+                            addr.addr.resolve(value).into_owned(),
+                            addr.display_name
+                                .as_ref()
+                                .map(|n| n.resolve(value).into_owned()),
+                        )
+                    })
+                    .collect(),
+            )),
+            Err(Error::Invalid) => Some(None),
+            Err(Error::EncodedWords) => None,
+        }
+    }
+
+    /// Split into headers, i.e., lines and their continuation lines.
+    fn headers(corpus: &[u8]) -> Vec<&[u8]> {
+        let mut headers = Vec::new();
+        let mut start = 0;
+        for (i, b) in corpus.iter().enumerate() {
+            let next = corpus.get(i + 1);
+            if *b == b'\n' && next != Some(&b' ') && next != Some(&b'\t') {
+                headers.push(&corpus[start..=i]);
+                start = i + 1;
+            }
+        }
+        headers
+    }
+
+    /// Headers must only be skipped without parsing if none of their addresses and names contains
+    /// the literal of the matcher. Every substring (of some lengths) of the parsed strings is such
+    /// a literal, in both cases.
+    fn check_may_contain(line: &[u8], parsed: &[(String, Option<String>)]) {
+        let value = header_value(line);
+        let mut scratch = Vec::new();
+        for s in parsed
+            .iter()
+            .flat_map(|(addr, name)| std::iter::once(addr).chain(name))
+        {
+            let chars = s.chars().collect::<Vec<_>>();
+            for len in [1, 4] {
+                for window in chars.windows(len) {
+                    let text = window.iter().collect::<String>();
+                    for (text, ignore_case) in [(text.clone(), false), (text.to_lowercase(), true)]
+                    {
+                        let literal = Literal {
+                            text: &text,
+                            ignore_case,
+                        };
+                        assert!(
+                            may_contain(value, &literal, &mut scratch),
+                            "{:?} (ignore case: {}) in {:?}",
+                            text,
+                            ignore_case,
+                            String::from_utf8_lossy(line)
+                        );
+                    }
+                }
+            }
+        }
+    }
+
+    fn check(line: &[u8]) -> bool {
+        if let Some(parsed) = with_mailparse(line) {
+            check_may_contain(line, &parsed);
+        }
+        match with_addrparse(line) {
+            Some(parsed) => {
+                assert_eq!(
+                    parsed,
+                    with_mailparse(line),
+                    "{:?}",
+                    String::from_utf8_lossy(line)
+                );
+                true
+            }
+            None => false,
+        }
+    }
+
+    #[test]
+    fn same_as_mailparse_for_corpus() {
+        let corpus = include_bytes!("../testdata/address_headers.txt");
+        let mut num_checked = 0;
+        for header in headers(corpus) {
+            if check(header) {
+                num_checked += 1;
+            }
+        }
+        assert!(num_checked > 80);
+    }
+
+    #[test]
+    fn same_as_mailparse_for_generated_headers() {
+        const PARTS: &[&[u8]] = &[
+            b"a",
+            b"Name",
+            b"x@ex.org",
+            b"@",
+            b" ",
+            b"  ",
+            b"\t",
+            b"\n ",
+            b"\r\n\t",
+            b"\"",
+            b"\\",
+            b"<",
+            b">",
+            b",",
+            b";",
+            b":",
+            b"(",
+            b")",
+            b"\xe9",
+            b"\xa0",
+            b"\x85",
+            b"\r",
+            b"=",
+        ];
+        // xorshift, so that failures are reproducible.
+        let mut state = 0x2545_f491_4f6c_dd1du64;
+        let mut next = move || {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state
+        };
+        for _ in 0..200_000 {
+            let mut line = b"To: ".to_vec();
+            for _ in 0..next() % 12 {
+                line.extend_from_slice(PARTS[(next() % PARTS.len() as u64) as usize]);
+            }
+            line.push(b'\n');
+            check(&line);
+        }
+    }
+}
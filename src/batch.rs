@@ -0,0 +1,137 @@
+use crate::{CaseInsensitiveMatcher, FuzzyMatcher, Literal, Matcher, SubstringMatcher};
+use std::io::{BufRead, BufReader};
+use std::path::Path;
+
+/// The matcher of a single batch query. Unlike the matchers used for a single search, the kind of
+/// matcher is only known at runtime, because every query may specify its own options.
+#[derive(Clone)]
+pub enum QueryMatcher {
+    Substring(SubstringMatcher),
+    CaseInsensitiveSubstring(CaseInsensitiveMatcher<SubstringMatcher>),
+    Fuzzy(FuzzyMatcher),
+    CaseInsensitiveFuzzy(CaseInsensitiveMatcher<FuzzyMatcher>),
+}
+
+impl QueryMatcher {
+    fn with_options(pattern: String, ignore_case: bool, fuzzy: bool) -> Self {
+        match (ignore_case, fuzzy) {
+            (false, false) => QueryMatcher::Substring(SubstringMatcher::new(pattern)),
+            (true, false) => {
+                QueryMatcher::CaseInsensitiveSubstring(CaseInsensitiveMatcher::new(pattern))
+            }
+            (false, true) => QueryMatcher::Fuzzy(FuzzyMatcher::new(pattern)),
+            (true, true) => {
+                QueryMatcher::CaseInsensitiveFuzzy(CaseInsensitiveMatcher::new(pattern))
+            }
+        }
+    }
+}
+
+impl Matcher for QueryMatcher {
+    fn new(pattern: String) -> Self {
+        Query::parse(&pattern, false, false).matcher
+    }
+    fn matches(&self, s: &str) -> bool {
+        match self {
+            QueryMatcher::Substring(m) => m.matches(s),
+            QueryMatcher::CaseInsensitiveSubstring(m) => m.matches(s),
+            QueryMatcher::Fuzzy(m) => m.matches(s),
+            QueryMatcher::CaseInsensitiveFuzzy(m) => m.matches(s),
+        }
+    }
+    fn score(&self, s: &str) -> Option<i64> {
+        match self {
+            QueryMatcher::Substring(m) => m.score(s),
+            QueryMatcher::CaseInsensitiveSubstring(m) => m.score(s),
+            QueryMatcher::Fuzzy(m) => m.score(s),
+            QueryMatcher::CaseInsensitiveFuzzy(m) => m.score(s),
+        }
+    }
+    fn literal(&self) -> Option<Literal<'_>> {
+        match self {
+            QueryMatcher::Substring(m) => m.literal(),
+            QueryMatcher::CaseInsensitiveSubstring(m) => m.literal(),
+            QueryMatcher::Fuzzy(m) => m.literal(),
+            QueryMatcher::CaseInsensitiveFuzzy(m) => m.literal(),
+        }
+    }
+}
+
+/// Matches if any of the queries matches. Used to collect the addresses for all queries in a
+/// single scan.
+#[derive(Clone)]
+pub struct AnyMatcher(Vec<QueryMatcher>);
+
+impl AnyMatcher {
+    pub fn for_queries(queries: &[Query]) -> Self {
+        AnyMatcher(queries.iter().map(|q| q.matcher.clone()).collect())
+    }
+}
+
+impl Matcher for AnyMatcher {
+    /// One query per line.
+    fn new(pattern: String) -> Self {
+        AnyMatcher(
+            pattern
+                .lines()
+                .map(|l| QueryMatcher::new(l.to_owned()))
+                .collect(),
+        )
+    }
+    fn matches(&self, s: &str) -> bool {
+        self.0.iter().any(|m| m.matches(s))
+    }
+    fn score(&self, s: &str) -> Option<i64> {
+        self.0.iter().filter_map(|m| m.score(s)).max()
+    }
+}
+
+pub struct Query {
+    pub search: String,
+    pub matcher: QueryMatcher,
+}
+
+impl Query {
+    /// Parse a query line. A line may start with its own matcher options (`-i`, `-f` or `-if`),
+    /// which replace the defaults. `--` ends the options (and selects case sensitive substring
+    /// matching), so that search strings starting with `-` can be written as `-- -foo`.
+    pub fn parse(line: &str, ignore_case: bool, fuzzy: bool) -> Self {
+        let (flags, rest) = match line.find(' ') {
+            Some(i) => (&line[..i], &line[i + 1..]),
+            None => (line, ""),
+        };
+        let (search, ignore_case, fuzzy) = if flags == "--" {
+            (rest, false, false)
+        } else if flags.len() > 1
+            && flags.starts_with('-')
+            && flags[1..].chars().all(|c| c == 'i' || c == 'f')
+        {
+            (rest, flags.contains('i'), flags.contains('f'))
+        } else {
+            (line, ignore_case, fuzzy)
+        };
+        Query {
+            search: search.to_owned(),
+            matcher: QueryMatcher::with_options(search.to_owned(), ignore_case, fuzzy),
+        }
+    }
+}
+
+/// Read one query per line from `path` (or stdin for `-`). Empty lines are skipped. Queries
+/// without options of their own use `ignore_case` and `fuzzy`.
+pub fn read_queries(path: &Path, ignore_case: bool, fuzzy: bool) -> std::io::Result<Vec<Query>> {
+    let reader: Box<dyn BufRead> = if path == Path::new("-") {
+        Box::new(BufReader::new(std::io::stdin()))
+    } else {
+        Box::new(BufReader::new(std::fs::File::open(path)?))
+    };
+    let mut queries = Vec::new();
+    for line in reader.lines() {
+        let line = line?;
+        let line = line.trim_end_matches('\r');
+        if !line.is_empty() {
+            queries.push(Query::parse(line, ignore_case, fuzzy));
+        }
+    }
+    Ok(queries)
+}
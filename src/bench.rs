@@ -0,0 +1,126 @@
+use crate::common::page_cache_residency;
+use crate::{
+    auto_backend, scan_with, BackendError, BackendKind, Matcher, ScanOptions, SubstringMatcher,
+    RESIDENCY_SAMPLES,
+};
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use structopt::StructOpt;
+
+#[derive(StructOpt)]
+pub struct BenchOptions {
+    #[structopt(
+        short = "r",
+        long = "runs",
+        help = "Number of runs per backend, of which the fastest is reported",
+        default_value = "1"
+    )]
+    runs: usize,
+    #[structopt(
+        long = "cold",
+        help = "Evict the mails from the page cache before every run to measure reading them from disk"
+    )]
+    cold: bool,
+    #[structopt(help = "base directory for recursive mail search", parse(from_os_str))]
+    dir: PathBuf,
+}
+
+/// Drop the (clean) pages of all files below `dir` from the page cache (see posix_fadvise(2)).
+/// Does not require any privileges, unlike `/proc/sys/vm/drop_caches`.
+fn evict(dir: &Path) {
+    let mut dirs = vec![dir.to_owned()];
+    while let Some(dir) = dirs.pop() {
+        let entries = match std::fs::read_dir(&dir) {
+            Ok(entries) => entries,
+            Err(_) => continue,
+        };
+        for entry in entries.flatten() {
+            match entry.file_type() {
+                Ok(t) if t.is_dir() => dirs.push(entry.path()),
+                Ok(t) if t.is_file() => {
+                    if let Ok(file) = std::fs::File::open(entry.path()) {
+                        // Safety: posix_fadvise has no memory safety requirements.
+                        unsafe {
+                            libc::posix_fadvise(file.as_raw_fd(), 0, 0, libc::POSIX_FADV_DONTNEED)
+                        };
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+struct Measurement {
+    num_mails: u64,
+    bytes_read: u64,
+    syscalls: u64,
+    elapsed: Duration,
+}
+
+fn measure(
+    backend: BackendKind,
+    options: &BenchOptions,
+    scan_options: &ScanOptions,
+) -> Result<Measurement, BackendError> {
+    let mut best: Option<Measurement> = None;
+    for _ in 0..options.runs.max(1) {
+        if options.cold {
+            evict(&options.dir);
+        }
+        let start = Instant::now();
+        let addrs = scan_with(
+            backend,
+            options.dir.clone(),
+            SubstringMatcher::new(String::new()),
+            scan_options,
+            None,
+        )?;
+        let elapsed = start.elapsed();
+        if best.as_ref().is_none_or(|b| elapsed < b.elapsed) {
+            let io_stats = addrs.io_stats();
+            best = Some(Measurement {
+                num_mails: addrs.num_mails(),
+                bytes_read: io_stats.bytes_read,
+                syscalls: io_stats.syscalls,
+                elapsed,
+            });
+        }
+    }
+    Ok(best.unwrap())
+}
+
+/// Scan `options.dir` with all backends and print a comparison.
+pub fn run(options: &BenchOptions, scan_options: &ScanOptions) {
+    if options.cold {
+        evict(&options.dir);
+    }
+    if let Some(residency) = page_cache_residency(&options.dir, RESIDENCY_SAMPLES) {
+        println!(
+            "Page cache: {:.0}% of the sampled mail pages are cached, auto picks the {} backend",
+            residency * 100.0,
+            auto_backend(&options.dir, scan_options).name()
+        );
+    }
+    println!(
+        "{:<10} {:>10} {:>10} {:>12} {:>10} {:>10}",
+        "backend", "files", "files/s", "bytes read", "syscalls", "time"
+    );
+    for backend in BackendKind::ALL {
+        match measure(*backend, options, scan_options) {
+            Ok(m) => println!(
+                "{:<10} {:>10} {:>10.0} {:>12} {:>10} {:>9.3}s",
+                backend.name(),
+                m.num_mails,
+                m.num_mails as f64 / m.elapsed.as_secs_f64(),
+                m.bytes_read,
+                m.syscalls,
+                m.elapsed.as_secs_f64()
+            ),
+            Err(BackendError::NotSupported(reason)) => {
+                println!("{:<10} not supported ({})", backend.name(), reason)
+            }
+        }
+    }
+}
@@ -1,51 +1,475 @@
-use crate::Matcher;
-use core::sync::atomic::{AtomicUsize, Ordering};
-use mailparse::{addrparse_header, parse_header, MailAddr, SingleInfo};
-use std::collections::HashMap;
+use crate::addrparse;
+use crate::interner::{Interner, StrId};
+use crate::output::{write_entries, OutputOptions, Summary};
+use crate::topk::{HeavyHitters, TopK};
+use crate::{Literal, Matcher};
+use bstr::ByteSlice;
+use core::cmp::Ordering;
+use core::sync::atomic::{self, AtomicUsize};
+use mailparse::{addrparse_header, parse_header, MailAddr};
+use rustc_hash::{FxHashMap, FxHashSet};
+use std::ffi::{CStr, CString, OsStr};
 use std::io::Write;
-use std::path::PathBuf;
-use walkdir::WalkDir;
-
-pub fn find_mails(dir: PathBuf) -> impl Iterator<Item = PathBuf> {
-    WalkDir::new(dir).into_iter().filter_map(|entry| {
-        let entry = match entry {
-            Ok(entry) => entry,
-            Err(e) => {
-                eprintln!("Dir error: {}", e);
-                return None;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{DirEntryExt, OpenOptionsExt};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// Maximum number of mails that are queued between directory traversal and the workers.
+const MAIL_QUEUE_SIZE: usize = 1 << 12;
+/// Number of mails that a worker takes from the queue at once.
+const MAIL_BATCH_SIZE: usize = 32;
+
+/// Name of the maildir folder that the directory `dir` belongs to. Mails are stored in the
+/// cur/new/tmp subdirectories of a maildir folder.
+fn maildir_folder(dir: &Path) -> String {
+    let mut dir = Some(dir);
+    if let Some(name) = dir.and_then(|d| d.file_name()) {
+        if name == "cur" || name == "new" || name == "tmp" {
+            dir = dir.and_then(|d| d.parent());
+        }
+    }
+    dir.and_then(|d| d.file_name())
+        .and_then(|name| name.to_str())
+        .unwrap_or("")
+        .to_owned()
+}
+
+/// A directory that contains mails. It is opened (as an `O_PATH` descriptor) once, so that mails
+/// can be opened relative to it without resolving the whole path again for every mail.
+pub struct MailDir {
+    path: PathBuf,
+    fd: std::fs::File,
+    /// Shared by the data of all addresses that were last seen in this folder.
+    folder: Arc<str>,
+    /// Whether this is the `cur` or `new` directory of a maildir (see `Mail::is_delivered`).
+    delivered: bool,
+}
+
+impl MailDir {
+    fn open(path: PathBuf) -> std::io::Result<Self> {
+        let fd = std::fs::OpenOptions::new()
+            .read(true)
+            .custom_flags(libc::O_PATH | libc::O_DIRECTORY | libc::O_CLOEXEC)
+            .open(&path)?;
+        let folder = maildir_folder(&path).into();
+        let delivered = path
+            .file_name()
+            .is_some_and(|name| name == "cur" || name == "new");
+        Ok(MailDir {
+            path,
+            fd,
+            folder,
+            delivered,
+        })
+    }
+}
+
+/// A mail, i.e., a file name within a `MailDir`.
+pub struct Mail {
+    dir: Arc<MailDir>,
+    /// Shared with the operations of the IO-uring backend that open the mail.
+    name: Arc<CStr>,
+}
+
+impl Mail {
+    pub fn path(&self) -> PathBuf {
+        self.dir.path.join(OsStr::from_bytes(self.name.to_bytes()))
+    }
+
+    /// Descriptor of the directory that contains the mail (valid as long as the mail is alive).
+    pub fn dir_fd(&self) -> RawFd {
+        self.dir.fd.as_raw_fd()
+    }
+
+    /// File name of the mail, relative to `dir_fd`.
+    pub fn file_name(&self) -> &Arc<CStr> {
+        &self.name
+    }
+
+    /// Whether the mail is in the `cur` or `new` directory of a maildir, i.e., has been
+    /// delivered completely. Such files are never modified in place, only renamed or deleted (see
+    /// maildir(5)). Any other file may still be written to.
+    pub fn is_delivered(&self) -> bool {
+        self.dir.delivered
+    }
+
+    pub fn info(&self) -> MailInfo<'_> {
+        // Maildir file names start with the delivery time, e.g. "1600000000.M1P2.host:2,S".
+        let timestamp = std::str::from_utf8(self.name.to_bytes())
+            .ok()
+            .and_then(|name| name.split('.').next())
+            .and_then(|time| time.parse().ok());
+        MailInfo {
+            timestamp,
+            folder: &self.dir.folder,
+        }
+    }
+}
+
+/// Send `mails` to the workers in batches of (at most) `MAIL_BATCH_SIZE`. The inode numbers are
+/// dropped.
+fn send_batches(mails: Vec<(u64, Mail)>, sender: &crossbeam_channel::Sender<Vec<Mail>>) {
+    let mut mails = mails.into_iter().map(|(_, mail)| mail);
+    loop {
+        let batch = mails.by_ref().take(MAIL_BATCH_SIZE).collect::<Vec<_>>();
+        // The receiver only goes away if nobody is interested in the remaining mails anymore.
+        if batch.is_empty() || sender.send(batch).is_err() {
+            break;
+        }
+    }
+}
+
+/// Read the directories received from `dirs` and send all files to `mails` and all subdirectories
+/// back to `dir_sender`. `pending` counts the directories that have been queued, but not read yet:
+/// Whoever finishes the last one stops all walkers (there is one `None` for every walker).
+///
+/// With `inode_order`, the files of a directory are only sent once the whole directory has been
+/// read, sorted by inode number.
+///
+/// Special files and the files in the `tmp` directory of a maildir (which are still being
+/// delivered) are skipped.
+fn walk_dirs(
+    dirs: crossbeam_channel::Receiver<Option<PathBuf>>,
+    dir_sender: crossbeam_channel::Sender<Option<PathBuf>>,
+    pending: &AtomicUsize,
+    num_walkers: usize,
+    inode_order: bool,
+    mails: crossbeam_channel::Sender<Vec<Mail>>,
+) {
+    // Nul terminated file names are built here and then copied into their `Mail`.
+    let mut name_buf = Vec::new();
+    while let Ok(Some(dir)) = dirs.recv() {
+        let in_delivery =
+            dir.file_name().is_some_and(|name| name == "tmp") && dir.with_file_name("cur").is_dir();
+        match std::fs::read_dir(&dir) {
+            Ok(entries) => {
+                // Only opened once the first mail is found, many directories do not contain any.
+                // If that fails, the mails are skipped, but the subdirectories are still walked.
+                let mut mail_dir = None;
+                let mut found = Vec::new();
+                for entry in entries {
+                    let entry = match entry.and_then(|entry| Ok((entry.file_type()?, entry))) {
+                        Ok((file_type, entry)) if file_type.is_dir() => {
+                            pending.fetch_add(1, atomic::Ordering::SeqCst);
+                            dir_sender.send(Some(entry.path())).unwrap();
+                            continue;
+                        }
+                        Ok(_) if in_delivery => continue,
+                        // Fifos, sockets and devices are no mails, and opening them may block.
+                        Ok((file_type, _)) if !file_type.is_file() && !file_type.is_symlink() => {
+                            continue
+                        }
+                        Ok((_, entry)) => entry,
+                        Err(e) => {
+                            eprintln!("Dir error: {}: {}", dir.display(), e);
+                            continue;
+                        }
+                    };
+                    let opened = mail_dir.get_or_insert_with(|| {
+                        MailDir::open(dir.clone())
+                            .map(Arc::new)
+                            .map_err(|e| eprintln!("Dir error: {}: {}", dir.display(), e))
+                    });
+                    let mail_dir = match opened {
+                        Ok(mail_dir) => mail_dir.clone(),
+                        Err(()) => continue,
+                    };
+                    name_buf.clear();
+                    name_buf.extend_from_slice(entry.file_name().as_bytes());
+                    name_buf.push(0);
+                    let name = match CStr::from_bytes_with_nul(&name_buf) {
+                        Ok(name) => name.into(),
+                        Err(_) => continue, // File names cannot contain NUL bytes.
+                    };
+                    let mail = Mail {
+                        dir: mail_dir,
+                        name,
+                    };
+                    found.push((entry.ino(), mail));
+                    if !inode_order && found.len() == MAIL_BATCH_SIZE {
+                        send_batches(std::mem::take(&mut found), &mails);
+                    }
+                }
+                if inode_order {
+                    found.sort_unstable_by_key(|(ino, _)| *ino);
+                }
+                send_batches(found, &mails);
             }
-        };
+            Err(e) => eprintln!("Dir error: {}: {}", dir.display(), e),
+        }
+        if pending.fetch_sub(1, atomic::Ordering::SeqCst) == 1 {
+            for _ in 0..num_walkers {
+                dir_sender.send(None).unwrap();
+            }
+        }
+    }
+}
 
-        if entry.file_type().is_dir() {
-            return None;
+/// Every queued mail may keep its directory open, so make sure that the (soft) limit of open files
+/// does not get in the way.
+fn raise_fd_limit() {
+    let mut limit = libc::rlimit {
+        rlim_cur: 0,
+        rlim_max: 0,
+    };
+    // Safety: limit is a valid rlimit struct that is filled in by getrlimit.
+    unsafe {
+        if libc::getrlimit(libc::RLIMIT_NOFILE, &mut limit) == 0 && limit.rlim_cur < limit.rlim_max
+        {
+            limit.rlim_cur = limit.rlim_max;
+            libc::setrlimit(libc::RLIMIT_NOFILE, &limit);
         }
+    }
+}
 
-        Some(entry.into_path())
-    })
+/// Fraction of the first pages of (up to) `max_files` files below `dir` that are in the page
+/// cache (see mincore(2)), or `None` if there are no (non-empty) files.
+pub fn page_cache_residency(dir: &Path, max_files: usize) -> Option<f64> {
+    const PAGES_PER_FILE: usize = 4;
+    // Safety: sysconf has no memory safety requirements.
+    let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as usize;
+    let mut resident = 0;
+    let mut total = 0;
+    let mut num_files = 0;
+    let mut dirs = vec![dir.to_owned()];
+    while let Some(dir) = dirs.pop() {
+        let entries = match std::fs::read_dir(&dir) {
+            Ok(entries) => entries,
+            Err(_) => continue,
+        };
+        for entry in entries.flatten() {
+            if num_files == max_files {
+                break;
+            }
+            match entry.file_type() {
+                Ok(t) if t.is_dir() => dirs.push(entry.path()),
+                Ok(t) if t.is_file() => {
+                    let file = match std::fs::File::open(entry.path()) {
+                        Ok(file) => file,
+                        Err(_) => continue,
+                    };
+                    let len = file.metadata().map(|m| m.len() as usize).unwrap_or(0);
+                    let len = len.min(PAGES_PER_FILE * page_size);
+                    if len == 0 {
+                        continue;
+                    }
+                    let num_pages = len.div_ceil(page_size);
+                    let mut pages = [0u8; PAGES_PER_FILE];
+                    // Safety: The mapping is only passed to mincore, which writes one byte per
+                    // page into pages, and unmapped right away.
+                    unsafe {
+                        let addr = libc::mmap(
+                            std::ptr::null_mut(),
+                            len,
+                            libc::PROT_READ,
+                            libc::MAP_SHARED,
+                            file.as_raw_fd(),
+                            0,
+                        );
+                        if addr == libc::MAP_FAILED {
+                            continue;
+                        }
+                        let res = libc::mincore(addr, len, pages.as_mut_ptr());
+                        libc::munmap(addr, len);
+                        if res != 0 {
+                            continue;
+                        }
+                    }
+                    resident += pages[..num_pages].iter().filter(|p| *p & 1 != 0).count();
+                    total += num_pages;
+                    num_files += 1;
+                }
+                _ => {}
+            }
+        }
+        if num_files == max_files {
+            break;
+        }
+    }
+    if total == 0 {
+        None
+    } else {
+        Some(resident as f64 / total as f64)
+    }
 }
 
+/// All files below a directory. The directory tree is traversed by several threads in parallel
+/// which feed the mails to the workers through a bounded queue, so processing starts right away
+/// and memory usage does not depend on the number of mails.
 pub struct Mails {
-    mails: Vec<PathBuf>,
-    current: AtomicUsize,
+    receiver: crossbeam_channel::Receiver<Vec<Mail>>,
 }
 
 impl Mails {
-    pub fn new(dir: PathBuf) -> Self {
-        Mails {
-            mails: find_mails(dir).collect(),
-            current: AtomicUsize::new(0),
-        }
-    }
-    pub fn get(&self) -> Option<PathBuf> {
-        // We could do some unsafe magic here to avoid the clone, but so far this is very much not
-        // a bottle neck.
-        let index = self.current.fetch_add(1, Ordering::SeqCst);
-        if index < self.mails.len() {
-            Some(self.mails[index].clone())
-        } else {
-            None
+    /// With `inode_order`, the mails of each directory are handed out sorted by inode number,
+    /// which roughly matches their order on disk on many file systems (see `walk_dirs`).
+    pub fn new(dir: PathBuf, inode_order: bool) -> Self {
+        raise_fd_limit();
+        let (sender, receiver) = crossbeam_channel::bounded(MAIL_QUEUE_SIZE / MAIL_BATCH_SIZE);
+        if !dir.is_dir() {
+            // Same as walking a tree that only consists of this one file.
+            let parent = match dir.parent() {
+                Some(parent) if parent != Path::new("") => parent.to_owned(),
+                _ => PathBuf::from("."),
+            };
+            let name = dir.file_name().map(|n| n.as_bytes().to_vec());
+            match (
+                MailDir::open(parent),
+                name.and_then(|n| CString::new(n).ok()),
+            ) {
+                (Ok(mail_dir), Some(name)) => {
+                    let _ = sender.send(vec![Mail {
+                        dir: Arc::new(mail_dir),
+                        name: name.into(),
+                    }]);
+                }
+                (Err(e), _) => eprintln!("Dir error: {}: {}", dir.display(), e),
+                (_, None) => eprintln!("Dir error: {}: Not a file", dir.display()),
+            }
+            return Mails { receiver };
+        }
+
+        let num_walkers = num_cpus::get();
+        let (dir_sender, dir_receiver) = crossbeam_channel::unbounded();
+        let pending = Arc::new(AtomicUsize::new(1));
+        dir_sender.send(Some(dir)).unwrap();
+        for _ in 0..num_walkers {
+            let dirs = dir_receiver.clone();
+            let dir_sender = dir_sender.clone();
+            let pending = pending.clone();
+            let mails = sender.clone();
+            std::thread::spawn(move || {
+                walk_dirs(dirs, dir_sender, &pending, num_walkers, inode_order, mails)
+            });
+        }
+        Mails { receiver }
+    }
+
+    /// A queue for a single worker, which takes a batch of mails at a time.
+    pub fn queue(&self) -> MailQueue<'_> {
+        MailQueue {
+            mails: self,
+            batch: Vec::new().into_iter(),
+        }
+    }
+}
+
+/// The mails of a `Mails` that are handed out to one worker.
+pub struct MailQueue<'a> {
+    mails: &'a Mails,
+    batch: std::vec::IntoIter<Mail>,
+}
+
+impl Iterator for MailQueue<'_> {
+    type Item = Mail;
+
+    /// Returns the next mail, blocking until one has been found, or `None` once the whole tree
+    /// has been traversed.
+    fn next(&mut self) -> Option<Mail> {
+        loop {
+            if let Some(mail) = self.batch.next() {
+                return Some(mail);
+            }
+            self.batch = self.mails.receiver.recv().ok()?.into_iter();
+        }
+    }
+}
+
+/// Publishes the addresses collected so far while a scan is still running.
+///
+/// Each worker owns a clone and periodically hands over (and then resets) its collection, so the
+/// receiver has to merge all received collections (and the result of the scan, which may be an
+/// error, see `scan`) itself.
+#[derive(Clone)]
+pub struct Progress {
+    sender: crossbeam_channel::Sender<Result<AddrCollection, String>>,
+    last_update: Instant,
+}
+
+impl Progress {
+    const UPDATE_INTERVAL: Duration = Duration::from_millis(50);
+
+    pub fn new(sender: crossbeam_channel::Sender<Result<AddrCollection, String>>) -> Self {
+        Progress {
+            sender,
+            last_update: Instant::now(),
+        }
+    }
+
+    pub fn update(&mut self, addrs: &mut AddrCollection) {
+        if self.last_update.elapsed() >= Self::UPDATE_INTERVAL {
+            let _ = self
+                .sender
+                .send(Ok(std::mem::replace(addrs, AddrCollection::new())));
+            self.last_update = Instant::now();
+        }
+    }
+}
+
+/// Scan the mails below `dir` on `threads` threads (the calling thread being one of them). Each
+/// one runs `scan_thread` with its own (initially empty) collection, and the collections of all
+/// threads are merged in the end.
+pub fn scan_threads<M: Matcher>(
+    dir: PathBuf,
+    inode_order: bool,
+    threads: usize,
+    collect: &Collect,
+    matcher: M,
+    progress: Option<Progress>,
+    scan_thread: impl Fn(M, &'static Mails, AddrCollection, Option<Progress>) -> AddrCollection
+        + Clone
+        + Send
+        + 'static,
+) -> AddrCollection {
+    let mails = &*Box::leak(Box::new(Mails::new(dir, inode_order)));
+    let threads = (1..threads)
+        .map(|_| {
+            let m = matcher.clone();
+            let a = AddrCollection::collecting(collect);
+            let p = progress.clone();
+            let scan_thread = scan_thread.clone();
+            std::thread::spawn(move || scan_thread(m, mails, a, p))
+        })
+        .collect::<Vec<_>>();
+
+    let mut collections = vec![scan_thread(
+        matcher,
+        mails,
+        AddrCollection::collecting(collect),
+        progress,
+    )];
+    collections.extend(threads.into_iter().map(|thread| thread.join().unwrap()));
+    AddrCollection::merge_all(collections)
+}
+
+/// Process the mails of `mails` one after another with `process_mail`, which adds the addresses
+/// of a mail to the collection.
+pub fn scan_mails<M: Matcher>(
+    matcher: M,
+    mails: &Mails,
+    mut addrs: AddrCollection,
+    mut progress: Option<Progress>,
+    process_mail: impl Fn(Mail, &M, &mut AddrCollection),
+) -> AddrCollection {
+    for mail in mails.queue() {
+        process_mail(mail, &matcher, &mut addrs);
+        addrs.count_mails(1);
+        if let Some(progress) = &mut progress {
+            progress.update(&mut addrs);
         }
     }
+    addrs
+}
+
+/// Information about a mail that is derived from its location in the maildir.
+pub struct MailInfo<'a> {
+    /// Delivery time (seconds since the epoch) as encoded in the maildir file name.
+    pub timestamp: Option<i64>,
+    /// Name of the maildir folder the mail is stored in.
+    pub folder: &'a Arc<str>,
 }
 
 pub enum HeaderParseResult {
@@ -53,11 +477,188 @@ pub enum HeaderParseResult {
     Done,
 }
 
-pub fn process_mail_header(
+/// Size of the first read of a mail. Most headers fit into it.
+pub const FIRST_BLOCK_SIZE: usize = 4 * 1024; //4KB
+/// Size of all further reads of a mail with a longer header.
+pub const FOLLOW_UP_BLOCK_SIZE: usize = 64 * 1024; //64KB
+
+/// Parses the header of a single mail from the bytes of the file, fed in order and in chunks of
+/// any size. The reader does no IO itself, backends only have to supply the bytes (until it is
+/// done) in whatever way suits them.
+pub struct HeaderReader<'a> {
+    mail: MailInfo<'a>,
+    /// The start of a line that could not be parsed yet, because its end was not part of the
+    /// chunks fed so far.
+    pending: Vec<u8>,
+    /// Number of bytes fed so far, i.e., the file offset of the next chunk.
+    offset: usize,
+    /// Number of bytes parsed so far.
+    parsed: usize,
+    done: bool,
+    buffers: HeaderBuffers,
+}
+
+impl<'a> HeaderReader<'a> {
+    pub fn new(mail: MailInfo<'a>) -> Self {
+        HeaderReader {
+            mail,
+            pending: Vec::new(),
+            offset: 0,
+            parsed: 0,
+            done: false,
+            buffers: HeaderBuffers::default(),
+        }
+    }
+
+    /// File offset of the next chunk.
+    pub fn offset(&self) -> usize {
+        self.offset
+    }
+
+    /// Number of bytes the parser has looked at, i.e., the length of the header once done.
+    pub fn parsed(&self) -> usize {
+        self.parsed
+    }
+
+    /// How many bytes the next read should ask for.
+    pub fn next_read_size(&self) -> usize {
+        if self.offset == 0 {
+            FIRST_BLOCK_SIZE
+        } else {
+            FOLLOW_UP_BLOCK_SIZE
+        }
+    }
+
+    /// Parse the next chunk of the file and add the matching addresses to `addr_collection`. An
+    /// empty chunk marks the end of the file. Once this returns `Done`, the rest of the file is
+    /// not needed anymore.
+    pub fn feed(
+        &mut self,
+        chunk: &[u8],
+        matcher: &impl Matcher,
+        addr_collection: &mut AddrCollection,
+    ) -> HeaderParseResult {
+        if chunk.is_empty() {
+            self.done = true;
+        }
+        if self.done {
+            return HeaderParseResult::Done;
+        }
+        self.offset += chunk.len();
+
+        let mut pos = 0;
+        let result = if self.pending.is_empty() {
+            // Parse in place and only keep what is left.
+            let result = process_mail_header(
+                chunk,
+                &mut pos,
+                &self.mail,
+                matcher,
+                addr_collection,
+                &mut self.buffers,
+            );
+            if let HeaderParseResult::NeedMore = result {
+                self.pending.extend_from_slice(&chunk[pos..]);
+            }
+            result
+        } else {
+            self.pending.extend_from_slice(chunk);
+            let result = process_mail_header(
+                &self.pending,
+                &mut pos,
+                &self.mail,
+                matcher,
+                addr_collection,
+                &mut self.buffers,
+            );
+            self.pending.drain(..pos);
+            result
+        };
+        self.parsed += pos;
+        if let HeaderParseResult::Done = result {
+            self.done = true;
+        }
+        result
+    }
+}
+
+/// Whether `haystack` contains `needle` (which is lowercase), ignoring ASCII case.
+fn contains_ignore_ascii_case(haystack: &[u8], needle: &[u8]) -> bool {
+    let first = needle[0];
+    let mut start = 0;
+    while let Some(offset) = memchr::memchr2(first, first.to_ascii_uppercase(), &haystack[start..])
+    {
+        let candidate = start + offset;
+        match haystack.get(candidate..candidate + needle.len()) {
+            Some(c) if c.eq_ignore_ascii_case(needle) => return true,
+            Some(_) => start = candidate + 1,
+            None => return false,
+        }
+    }
+    false
+}
+
+/// Whether the raw `value` of an address header may contain an address or display name that
+/// contains `literal`, i.e., whether the header has to be parsed at all.
+///
+/// Parsing (see `mailparse::addrparse_header`) decodes the value as latin1 and mostly removes
+/// characters: quotes, the backslashes of escaped characters, comments and line breaks (which are
+/// replaced by whitespace). So for an ASCII literal without whitespace, quotes and backslashes, a
+/// match has to be part of the value with quotes and backslashes removed. Comments and RFC 2047
+/// encoded words are not handled, headers with them are always parsed.
+pub fn may_contain(value: &[u8], literal: &Literal, scratch: &mut Vec<u8>) -> bool {
+    let needle = literal.text.as_bytes();
+    if needle.is_empty()
+        || !needle
+            .iter()
+            .all(|b| b.is_ascii_graphic() && *b != b'"' && *b != b'\\')
+    {
+        return true;
+    }
+    if memchr::memchr(b'(', value).is_some() || value.find(b"=?").is_some() {
+        return true;
+    }
+    let value = if memchr::memchr2(b'"', b'\\', value).is_some() {
+        scratch.clear();
+        scratch.extend(value.iter().filter(|b| **b != b'"' && **b != b'\\'));
+        &scratch[..]
+    } else {
+        value
+    };
+    if literal.ignore_case {
+        contains_ignore_ascii_case(value, needle)
+    } else {
+        value.find(needle).is_some()
+    }
+}
+
+fn add_if_matching(
+    addr: &str,
+    display_name: Option<&str>,
+    mail: &MailInfo,
+    matcher: &impl Matcher,
+    addr_collection: &mut AddrCollection,
+) {
+    if matcher.matches(addr) || display_name.is_some_and(|n| matcher.matches(n)) {
+        addr_collection.add(addr, display_name, mail);
+    }
+}
+
+/// Memory that is reused for all headers of a mail.
+#[derive(Default)]
+struct HeaderBuffers {
+    /// See `may_contain`.
+    filtered: Vec<u8>,
+    addrs: Vec<addrparse::Address>,
+}
+
+fn process_mail_header(
     buf: &[u8],
     pos: &mut usize,
+    mail: &MailInfo,
     matcher: &impl Matcher,
     addr_collection: &mut AddrCollection,
+    buffers: &mut HeaderBuffers,
 ) -> HeaderParseResult {
     const MIN_OFFSET: usize = 5;
     while *pos + MIN_OFFSET < buf.len() {
@@ -89,23 +690,47 @@ pub fn process_mail_header(
                 }
             };
             let line = &buf[*pos..next_line_begin];
-            if let Ok(header) = parse_header(&line) {
-                if let Ok(iter) = addrparse_header(&header.0) {
-                    for addr in iter.into_inner() {
-                        if let MailAddr::Single(addr) = addr {
-                            if matcher.matches(&addr.addr)
-                                || addr
-                                    .display_name
-                                    .as_ref()
-                                    .map(|n| matcher.matches(n))
-                                    .unwrap_or(false)
-                            {
-                                addr_collection.add(addr);
+            let value = addrparse::header_value(line);
+            let may_match = matcher
+                .literal()
+                .is_none_or(|literal| may_contain(value, &literal, &mut buffers.filtered));
+            if !may_match {
+                *pos = next_line_begin;
+                continue;
+            }
+            match addrparse::parse_address_list(value, &mut buffers.addrs) {
+                Ok(()) => {
+                    for addr in buffers.addrs.drain(..) {
+                        let name = addr.display_name.as_ref().map(|n| n.resolve(value));
+                        add_if_matching(
+                            &addr.addr.resolve(value),
+                            name.as_deref(),
+                            mail,
+                            matcher,
+                            addr_collection,
+                        );
+                    }
+                }
+                Err(addrparse::Error::EncodedWords) => {
+                    if let Ok(header) = parse_header(line) {
+                        if let Ok(iter) = addrparse_header(&header.0) {
+                            for addr in iter.into_inner() {
+                                if let MailAddr::Single(addr) = addr {
+                                    add_if_matching(
+                                        &addr.addr,
+                                        addr.display_name.as_deref(),
+                                        mail,
+                                        matcher,
+                                        addr_collection,
+                                    );
+                                }
                             }
                         }
                     }
                 }
+                Err(addrparse::Error::Invalid) => {}
             }
+            buffers.addrs.clear();
 
             *pos = next_line_begin;
         } else {
@@ -120,64 +745,460 @@ pub fn process_mail_header(
 }
 
 #[derive(Default)]
-struct AddrData {
-    name_variants: HashMap<String, u64>,
-    occurences: u64,
+pub struct AddrData {
+    /// Name variants (interned by the collection, see `Entry::sorted_name_variants`) and how
+    /// often each of them was seen. Most addresses only have a few.
+    pub name_variants: Vec<(StrId, u64)>,
+    pub occurences: u64,
+    /// Delivery time of the oldest mail the address was found in.
+    pub first_seen: Option<i64>,
+    /// Delivery time of the newest mail the address was found in.
+    pub last_seen: Option<i64>,
+    /// Folder of the newest mail the address was found in.
+    pub last_folder: Option<Arc<str>>,
+}
+
+impl AddrData {
+    fn seen(&mut self, timestamp: Option<i64>, folder: &Arc<str>) {
+        if let Some(t) = timestamp {
+            self.first_seen = Some(self.first_seen.map_or(t, |first| first.min(t)));
+        }
+        if self.last_folder.is_none() || timestamp > self.last_seen {
+            self.last_seen = self.last_seen.max(timestamp);
+            self.last_folder = Some(folder.clone());
+        }
+    }
+
+    fn add_name(&mut self, name: StrId, occurences: u64) {
+        match self.name_variants.iter_mut().find(|(n, _)| *n == name) {
+            Some((_, n)) => *n += occurences,
+            None => self.name_variants.push((name, occurences)),
+        }
+    }
+
+    /// Merge `other`, whose name variants have already been interned by this collection.
+    fn merge(&mut self, other: AddrData) {
+        self.occurences += other.occurences;
+        for (name, occurences) in other.name_variants {
+            self.add_name(name, occurences);
+        }
+        self.first_seen = match (self.first_seen, other.first_seen) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (a, b) => a.or(b),
+        };
+        if other.last_folder.is_some()
+            && (self.last_folder.is_none() || other.last_seen > self.last_seen)
+        {
+            self.last_folder = other.last_folder;
+        }
+        self.last_seen = self.last_seen.max(other.last_seen);
+    }
+
+    /// Number of occurences weighted by how recently the address was last seen (relative to
+    /// `now`).
+    fn frecency(&self, now: i64) -> u64 {
+        const DAY: i64 = 24 * 60 * 60;
+        let weight = match self.last_seen.map(|t| (now - t) / DAY) {
+            Some(age) if age <= 4 => 100,
+            Some(age) if age <= 14 => 70,
+            Some(age) if age <= 31 => 50,
+            Some(age) if age <= 90 => 30,
+            _ => 10,
+        };
+        self.occurences.saturating_mul(weight)
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum SortOrder {
+    /// Most frequent addresses first
+    Count,
+    /// Most recently seen addresses first
+    Recency,
+    /// Alphabetically by address
+    Alpha,
+    /// Best matches first
+    Score,
+    /// Frequently and recently seen addresses first
+    Frecency,
+}
+
+impl SortOrder {
+    pub const VARIANTS: &'static [&'static str] =
+        &["count", "recency", "alpha", "score", "frecency"];
+}
+
+impl std::str::FromStr for SortOrder {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "count" => Ok(SortOrder::Count),
+            "recency" => Ok(SortOrder::Recency),
+            "alpha" => Ok(SortOrder::Alpha),
+            "score" => Ok(SortOrder::Score),
+            "frecency" => Ok(SortOrder::Frecency),
+            _ => Err(format!("Unknown sort order: {}", s)),
+        }
+    }
+}
+
+/// An address and its data, together with how well it matches the search.
+pub struct Entry<'a> {
+    pub addr: &'a str,
+    pub data: &'a AddrData,
+    pub score: i64,
+    /// Of the collection, for resolving the name variants.
+    strings: &'a Interner,
+}
+
+impl<'a> Entry<'a> {
+    fn names(&self) -> impl Iterator<Item = (&'a str, u64)> + '_ {
+        let strings = self.strings;
+        self.data
+            .name_variants
+            .iter()
+            .map(move |(name, n)| (strings.resolve(*name), *n))
+    }
+
+    /// Name variants ordered from most to least frequent.
+    pub fn sorted_name_variants(&self) -> Vec<(&'a str, u64)> {
+        let mut variants = self.names().collect::<Vec<_>>();
+        variants.sort_by(|(name_a, n_a), (name_b, n_b)| n_b.cmp(n_a).then(name_a.cmp(name_b)));
+        variants
+    }
+
+    /// The most frequent name variant, used as the display name.
+    pub fn preferred_name(&self) -> Option<&'a str> {
+        self.names()
+            .min_by(|(name_a, n_a), (name_b, n_b)| n_b.cmp(n_a).then(name_a.cmp(name_b)))
+            .map(|(name, _)| name)
+    }
+
+    fn matches(&self, matcher: &impl Matcher) -> bool {
+        matcher.matches(self.addr) || self.names().any(|(name, _)| matcher.matches(name))
+    }
+
+    /// Best score of the address or any of the name variants.
+    fn best_score(&self, matcher: &impl Matcher) -> Option<i64> {
+        std::iter::once(self.addr)
+            .chain(self.names().map(|(name, _)| name))
+            .filter_map(|s| matcher.score(s))
+            .max()
+    }
+}
+
+fn entry<'a>(strings: &'a Interner, addr: StrId, data: &'a AddrData) -> Entry<'a> {
+    Entry {
+        addr: strings.resolve(addr),
+        data,
+        score: 0,
+        strings,
+    }
+}
+
+/// IO that a backend performed to read the mails of a collection (not counting directory
+/// traversal).
+#[derive(Copy, Clone, Debug, Default)]
+pub struct IoStats {
+    pub bytes_read: u64,
+    pub syscalls: u64,
+}
+
+/// Which addresses a scan collects (see `AddrCollection::collecting`).
+#[derive(Clone, Debug)]
+pub enum Collect {
+    /// All of them, with all their data.
+    All,
+    /// Only the candidates for the most frequent ones, without any data (see `topk`).
+    Candidates(TopK),
+    /// Only these (lowercase) addresses, with all their data.
+    Only(Arc<FxHashSet<String>>),
 }
 
 pub struct AddrCollection {
-    addrs: HashMap<String, AddrData>,
+    /// Addresses and name variants. Both are stored only once, no matter how often they were seen.
+    strings: Interner,
+    addrs: FxHashMap<StrId, AddrData>,
+    /// Number of mails that were scanned to build this collection.
+    num_mails: u64,
+    io_stats: IoStats,
+    /// Reused to lowercase addresses (see `add`).
+    lowercase: String,
+    /// Counts addresses instead of `addrs` (see `Collect::Candidates`).
+    heavy_hitters: Option<HeavyHitters>,
+    /// See `Collect::Only`.
+    only: Option<Arc<FxHashSet<String>>>,
+}
+
+/// `s` in lowercase. `buf` is used (and overwritten) only if `s` is not lowercase already.
+fn lowercase<'a>(s: &'a str, buf: &'a mut String) -> &'a str {
+    if s.is_ascii() && !s.bytes().any(|b| b.is_ascii_uppercase()) {
+        return s;
+    }
+    buf.clear();
+    if s.is_ascii() {
+        buf.push_str(s);
+        buf.make_ascii_lowercase();
+    } else if s.contains('Σ') {
+        // The lowercase sigma depends on its position in a word, which only `to_lowercase` knows.
+        buf.push_str(&s.to_lowercase());
+    } else {
+        buf.extend(s.chars().flat_map(char::to_lowercase));
+    }
+    buf
 }
 
 impl AddrCollection {
-    pub fn add(&mut self, addr: SingleInfo) {
-        let data = self
-            .addrs
-            .entry(addr.addr.to_lowercase())
-            .or_insert(AddrData::default());
+    pub fn add(&mut self, addr: &str, display_name: Option<&str>, mail: &MailInfo) {
+        // Addresses are stored in lowercase, but most of them are lowercase already, so they are
+        // looked up as they are. Others are lowercased into a reused buffer.
+        let key = lowercase(addr, &mut self.lowercase);
+        if let Some(heavy_hitters) = &mut self.heavy_hitters {
+            heavy_hitters.add(key);
+            return;
+        }
+        if let Some(only) = &self.only {
+            if !only.contains(key) {
+                return;
+            }
+        }
+        let data = self.addrs.entry(self.strings.intern(key)).or_default();
         data.occurences += 1;
-        if let Some(name) = &addr.display_name {
-            *data.name_variants.entry(name.to_owned()).or_insert(0) += 1;
+        data.seen(mail.timestamp, mail.folder);
+        if let Some(name) = display_name {
+            data.add_name(self.strings.intern(name), 1);
         }
     }
 
-    pub fn merge(&mut self, other: AddrCollection) {
-        for (addr, other_data) in other.addrs {
-            if let Some(this_data) = self.addrs.get_mut(&addr) {
-                this_data.occurences += other_data.occurences;
-                for (name, occurences) in other_data.name_variants {
-                    *this_data.name_variants.entry(name).or_insert(0) += occurences;
-                }
+    /// Add already aggregated data for `addr` and its name variants (which replace those of
+    /// `data`), merging it with any data that is already present.
+    pub fn add_data<'n>(
+        &mut self,
+        addr: &str,
+        names: impl IntoIterator<Item = (&'n str, u64)>,
+        mut data: AddrData,
+    ) {
+        let strings = &mut self.strings;
+        data.name_variants = names
+            .into_iter()
+            .map(|(name, n)| (strings.intern(name), n))
+            .collect();
+        self.insert(addr, data);
+    }
+
+    /// Insert `data`, whose name variants have already been interned.
+    fn insert(&mut self, addr: &str, data: AddrData) {
+        let id = self.strings.intern(addr);
+        if let Some(this_data) = self.addrs.get_mut(&id) {
+            this_data.merge(data);
+        } else {
+            self.addrs.insert(id, data);
+        }
+    }
+
+    pub fn count_mails(&mut self, n: u64) {
+        self.num_mails += n;
+    }
+
+    pub fn num_mails(&self) -> u64 {
+        self.num_mails
+    }
+
+    pub fn count_io(&mut self, bytes_read: u64, syscalls: u64) {
+        self.io_stats.bytes_read += bytes_read;
+        self.io_stats.syscalls += syscalls;
+    }
+
+    pub fn io_stats(&self) -> IoStats {
+        self.io_stats
+    }
+
+    pub fn merge(&mut self, mut other: AddrCollection) {
+        self.num_mails += other.num_mails;
+        self.count_io(other.io_stats.bytes_read, other.io_stats.syscalls);
+        // Insert the smaller map into the larger one. Ids belong to their interner, so it is
+        // swapped as well.
+        if other.addrs.len() > self.addrs.len() {
+            std::mem::swap(&mut self.addrs, &mut other.addrs);
+            std::mem::swap(&mut self.strings, &mut other.strings);
+        }
+        self.addrs.reserve(other.addrs.len());
+        for (addr, mut other_data) in other.addrs {
+            for (name, _) in &mut other_data.name_variants {
+                *name = self.strings.intern(other.strings.resolve(*name));
+            }
+            self.insert(other.strings.resolve(addr), other_data);
+        }
+        match (&mut self.heavy_hitters, other.heavy_hitters) {
+            (Some(heavy_hitters), Some(other)) => heavy_hitters.merge(other),
+            (heavy_hitters @ None, other) => *heavy_hitters = other,
+            (Some(_), None) => {}
+        }
+    }
+
+    /// Merge all `collections` into one. Pairs of collections are merged in parallel, so this
+    /// takes about log2(n) merges instead of n.
+    pub fn merge_all(mut collections: Vec<AddrCollection>) -> AddrCollection {
+        while collections.len() > 1 {
+            let odd = if collections.len() % 2 == 1 {
+                collections.pop()
             } else {
-                self.addrs.insert(addr, other_data);
+                None
+            };
+            let mut pairs = Vec::new();
+            while let (Some(a), Some(b)) = (collections.pop(), collections.pop()) {
+                pairs.push((a, b));
             }
+            collections = std::thread::scope(|scope| {
+                let merges = pairs
+                    .into_iter()
+                    .map(|(mut a, b)| {
+                        scope.spawn(move || {
+                            a.merge(b);
+                            a
+                        })
+                    })
+                    .collect::<Vec<_>>();
+                merges
+                    .into_iter()
+                    .map(|merge| merge.join().unwrap())
+                    .collect()
+            });
+            collections.extend(odd);
         }
+        collections.pop().unwrap_or_else(AddrCollection::new)
+    }
+
+    /// Drop all addresses for which neither the address itself nor any of the name variants
+    /// match.
+    pub fn retain_matching(&mut self, matcher: &impl Matcher) {
+        let strings = &self.strings;
+        self.addrs
+            .retain(|addr, data| entry(strings, *addr, data).matches(matcher));
+    }
+
+    pub fn get<'a>(&'a self, addr: &str) -> Option<Entry<'a>> {
+        let id = self.strings.get(addr)?;
+        self.addrs
+            .get(&id)
+            .map(|data| entry(&self.strings, id, data))
+    }
+
+    pub fn len(&self) -> usize {
+        self.addrs.len()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = Entry<'_>> {
+        self.addrs
+            .iter()
+            .map(move |(addr, data)| entry(&self.strings, *addr, data))
     }
 
     pub fn new() -> Self {
         AddrCollection {
-            addrs: HashMap::new(),
+            strings: Interner::default(),
+            addrs: FxHashMap::default(),
+            num_mails: 0,
+            io_stats: IoStats::default(),
+            lowercase: String::new(),
+            heavy_hitters: None,
+            only: None,
         }
     }
 
-    pub fn print(self) {
-        let mut addrs = self.addrs.into_iter().collect::<Vec<_>>();
-        // Sort (reverse) so that high number of occurences are on top
-        addrs.sort_by_key(|(_, data)| u64::max_value() - data.occurences);
+    /// An empty collection for a scan that collects the addresses described by `collect`.
+    pub fn collecting(collect: &Collect) -> Self {
+        let mut collection = AddrCollection::new();
+        match collect {
+            Collect::All => {}
+            Collect::Candidates(top) => collection.heavy_hitters = Some(HeavyHitters::new(top)),
+            Collect::Only(addrs) => collection.only = Some(addrs.clone()),
+        }
+        collection
+    }
 
-        let stdout = std::io::stdout();
-        let mut stdout = stdout.lock();
+    /// The addresses that were found by a scan with `Collect::Candidates`.
+    pub fn into_candidates(self) -> FxHashSet<String> {
+        self.heavy_hitters
+            .map(HeavyHitters::into_candidates)
+            .unwrap_or_default()
+    }
 
-        let _ = writeln!(stdout, "");
-        for (addr, data) in addrs {
-            let name_variant = data
-                .name_variants
-                .iter()
-                .max_by_key(|(_, n)| *n)
-                .map(|(name, _)| name.as_str())
-                .unwrap_or("");
+    /// Drop all but the `k` most frequent addresses.
+    pub fn retain_top(&mut self, k: usize) {
+        if self.addrs.len() <= k {
+            return;
+        }
+        let mut counts = self
+            .addrs
+            .iter()
+            .map(|(addr, data)| (data.occurences, self.strings.resolve(*addr), *addr))
+            .collect::<Vec<_>>();
+        // Ties are broken by address, like in `entries`.
+        counts.sort_unstable_by(|(a_count, a, _), (b_count, b, _)| {
+            b_count.cmp(a_count).then(a.cmp(b))
+        });
+        let top = counts[..k]
+            .iter()
+            .map(|(_, _, id)| *id)
+            .collect::<FxHashSet<_>>();
+        self.addrs.retain(|addr, _| top.contains(addr));
+    }
+
+    /// All matching addresses that were seen at least `options.min_count` times, in the
+    /// requested order and limited to `options.limit` entries.
+    pub fn entries(&self, matcher: &impl Matcher, options: &OutputOptions) -> Vec<Entry<'_>> {
+        let mut entries = self
+            .iter()
+            .filter(|entry| entry.data.occurences >= options.min_count)
+            .filter_map(|entry| {
+                Some(Entry {
+                    score: entry.best_score(matcher)?,
+                    ..entry
+                })
+            })
+            .collect::<Vec<_>>();
 
-            let _ = writeln!(stdout, "{}\t{}", addr, name_variant);
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs() as i64)
+            .unwrap_or(0);
+        // Ties are always broken by number of occurences and then by address, so that the output
+        // is deterministic.
+        entries.sort_by(|a, b| {
+            let primary = match options.sort {
+                SortOrder::Count | SortOrder::Alpha => Ordering::Equal,
+                SortOrder::Recency => b.data.last_seen.cmp(&a.data.last_seen),
+                SortOrder::Score => b.score.cmp(&a.score),
+                SortOrder::Frecency => b.data.frecency(now).cmp(&a.data.frecency(now)),
+            };
+            let by_count = if options.sort == SortOrder::Alpha {
+                Ordering::Equal
+            } else {
+                b.data.occurences.cmp(&a.data.occurences)
+            };
+            primary.then(by_count).then(a.addr.cmp(b.addr))
+        });
+        if let Some(limit) = options.limit {
+            entries.truncate(limit);
         }
+        entries
+    }
+
+    pub fn print(self, matcher: &impl Matcher, options: &OutputOptions, elapsed: Duration) {
+        let stdout = std::io::stdout();
+        let mut stdout = std::io::BufWriter::new(stdout.lock());
+
+        let summary = Summary {
+            num_mails: self.num_mails,
+            elapsed,
+        };
+        let _ = write_entries(
+            &mut stdout,
+            self.entries(matcher, options),
+            &summary,
+            options,
+        )
+        .and_then(|_| stdout.flush());
     }
 }
@@ -1,27 +1,27 @@
-use crate::common::{process_mail_header, AddrCollection, HeaderParseResult, Mails};
-use crate::{Backend, Matcher};
+use crate::common::{
+    scan_mails, scan_threads, AddrCollection, Collect, HeaderParseResult, HeaderReader, Mail,
+    Progress,
+};
+use crate::{Backend, Matcher, ScanOptions};
 use std::io::Read;
 use std::path::PathBuf;
 
 fn process_mail(
-    p: PathBuf,
+    m: Mail,
     matcher: &impl Matcher,
     addrs: &mut AddrCollection,
 ) -> Result<(), Box<dyn std::error::Error>> {
-    let mut file = std::fs::File::open(p)?;
-    let expected_header_size = 4 * 1024; // 4KB
+    let mut reader = HeaderReader::new(m.info());
+    let mut file = std::fs::File::open(m.path())?;
+    // open and close
+    addrs.count_io(0, 2);
 
     let mut buf = Vec::new();
-    let mut total_read = 0;
-    let mut pos = 0;
     loop {
-        buf.resize(total_read + expected_header_size, 0);
-        let num_read = file.read(&mut buf[total_read..])?;
-        if num_read == 0 {
-            break;
-        }
-        total_read += num_read;
-        match process_mail_header(&buf[..total_read], &mut pos, matcher, addrs) {
+        buf.resize(reader.next_read_size(), 0);
+        let num_read = file.read(&mut buf)?;
+        addrs.count_io(num_read as u64, 1);
+        match reader.feed(&buf[..num_read], matcher, addrs) {
             HeaderParseResult::Done => break,
             HeaderParseResult::NeedMore => {}
         }
@@ -29,37 +29,38 @@ fn process_mail(
     Ok(())
 }
 
-fn process_mails(matcher: impl Matcher, mails: &Mails) -> AddrCollection {
-    let mut addrs = AddrCollection::new();
-    while let Some(path) = mails.get() {
-        let _ = process_mail(path, &matcher, &mut addrs);
-    }
-    addrs
+pub struct GenericBackend {
+    threads: usize,
+    inode_order: bool,
+    collect: Collect,
 }
 
-pub struct GenericBackend;
-
 impl Backend for GenericBackend {
-    fn construct() -> Result<Self, crate::BackendError> {
-        Ok(GenericBackend)
+    fn construct(options: &ScanOptions) -> Result<Self, crate::BackendError> {
+        Ok(GenericBackend {
+            threads: options.threads,
+            inode_order: options.inode_order,
+            collect: options.collect.clone(),
+        })
     }
-    fn run(self, dir: PathBuf, matcher: impl Matcher) {
-        let mails = &*Box::leak(Box::new(Mails::new(dir)));
-        let num_threads = num_cpus::get();
-        //let num_threads = 1;
-
-        let threads = (1..num_threads)
-            .into_iter()
-            .map(|_| {
-                let m = matcher.clone();
-                std::thread::spawn(move || process_mails(m, mails))
-            })
-            .collect::<Vec<_>>();
-
-        let mut addrs = process_mails(matcher, mails);
-        for thread in threads {
-            addrs.merge(thread.join().unwrap());
-        }
-        addrs.print();
+    fn run(
+        self,
+        dir: PathBuf,
+        matcher: impl Matcher,
+        progress: Option<Progress>,
+    ) -> AddrCollection {
+        scan_threads(
+            dir,
+            self.inode_order,
+            self.threads,
+            &self.collect,
+            matcher,
+            progress,
+            |matcher, mails, addrs, progress| {
+                scan_mails(matcher, mails, addrs, progress, |m, matcher, addrs| {
+                    let _ = process_mail(m, matcher, addrs);
+                })
+            },
+        )
     }
 }
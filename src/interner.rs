@@ -0,0 +1,74 @@
+//! Storing lots of (mostly short) strings without allocating for each of them.
+
+use rustc_hash::{FxHashMap, FxHasher};
+use std::convert::TryInto;
+use std::hash::Hasher;
+
+/// Marks the end of a chain of strings with the same hash (see `Interner::next`).
+const NO_ID: u32 = u32::MAX;
+
+/// Identifies a string of an `Interner`. Ids are only meaningful for the interner that created
+/// them.
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Debug)]
+pub struct StrId(u32);
+
+/// Distinct strings, stored back to back in a single buffer. Each string is stored once, so
+/// interning a string that is already known does not allocate.
+#[derive(Default)]
+pub struct Interner {
+    buf: String,
+    /// End of every string in `buf`, indexed by id. A string starts where the previous one ends.
+    ends: Vec<u32>,
+    /// The last string with a given hash. Strings with the same hash are looked up via `next`.
+    by_hash: FxHashMap<u64, u32>,
+    /// The previous string with the same hash, indexed by id.
+    next: Vec<u32>,
+}
+
+fn hash(s: &str) -> u64 {
+    let mut hasher = FxHasher::default();
+    hasher.write(s.as_bytes());
+    hasher.finish()
+}
+
+impl Interner {
+    pub fn resolve(&self, id: StrId) -> &str {
+        let index = id.0 as usize;
+        let start = match index {
+            0 => 0,
+            _ => self.ends[index - 1] as usize,
+        };
+        &self.buf[start..self.ends[index] as usize]
+    }
+
+    fn find(&self, s: &str, hash: u64) -> Option<StrId> {
+        let mut id = *self.by_hash.get(&hash)?;
+        while id != NO_ID {
+            if self.resolve(StrId(id)) == s {
+                return Some(StrId(id));
+            }
+            id = self.next[id as usize];
+        }
+        None
+    }
+
+    /// The id of `s`, if it has been interned.
+    pub fn get(&self, s: &str) -> Option<StrId> {
+        self.find(s, hash(s))
+    }
+
+    pub fn intern(&mut self, s: &str) -> StrId {
+        let hash = hash(s);
+        if let Some(id) = self.find(s, hash) {
+            return id;
+        }
+        let id = self.ends.len() as u32;
+        assert!(id != NO_ID, "Too many strings");
+        self.buf.push_str(s);
+        let end = self.buf.len().try_into().expect("Too many strings");
+        self.ends.push(end);
+        let previous = self.by_hash.insert(hash, id);
+        self.next.push(previous.unwrap_or(NO_ID));
+        StrId(id)
+    }
+}
@@ -1,18 +1,24 @@
-use core::cell::Cell;
+use core::cell::RefCell;
 use core::pin::Pin;
 use core::sync::atomic::AtomicU64;
 use core::task::Context;
 use core::task::Poll;
-use core::task::{RawWaker, RawWakerVTable, Waker};
-use io_uring::opcode::{types::Target, Close, Openat, Read};
-use io_uring::squeue::Entry;
-use std::collections::HashMap;
-use std::ffi::CString;
+use core::task::Waker;
+use io_uring::opcode::{AsyncCancel, Close, LinkTimeout, OpenAt2, PollAdd, Read, ReadFixed};
+use io_uring::register::Probe;
+use io_uring::squeue::{Entry, Flags};
+use io_uring::types;
+use std::any::Any;
+use std::collections::{HashMap, VecDeque};
+use std::ffi::CStr;
 use std::future::Future;
-use std::os::unix::ffi::OsStrExt;
-use std::os::unix::io::{AsRawFd, FromRawFd, IntoRawFd};
-use std::path::Path;
+use std::os::unix::io::{AsRawFd, FromRawFd, IntoRawFd, RawFd};
+use std::rc::Rc;
 use std::sync::atomic::Ordering;
+use std::sync::{Arc, Mutex};
+use std::task::Wake;
+use std::thread::ThreadId;
+use std::time::Duration;
 
 fn convert_result(ret: i32) -> std::io::Result<i32> {
     if ret >= 0 {
@@ -22,66 +28,422 @@ fn convert_result(ret: i32) -> std::io::Result<i32> {
     }
 }
 
-struct IouOp {
+/// Like `convert_result`, but for operations that are followed by a linked timeout (see
+/// `ExecutorBuilder::timeout`), which cancels them when it expires.
+fn convert_timed_result(ret: i32) -> std::io::Result<i32> {
+    if ret == -libc::ECANCELED {
+        Err(std::io::Error::new(
+            std::io::ErrorKind::TimedOut,
+            "Operation timed out",
+        ))
+    } else {
+        convert_result(ret)
+    }
+}
+
+/// The user data of a submission consists of the completion slot of the submitting `IouOp` and
+/// the index of the operation within its chain.
+const CHAIN_INDEX_BITS: u32 = 8;
+const MAX_CHAIN_LEN: usize = 1 << CHAIN_INDEX_BITS;
+
+/// User data of the poll on the wake eventfd (see `Ring::arm_wake_poll`).
+const WAKE_USER_DATA: u64 = u64::MAX;
+/// Set in the user data of the cancellation of a dropped operation (see `IouOp::drop`), whose
+/// remaining bits are the user data of the cancelled operation.
+const CANCEL_BIT: u64 = 1 << 63;
+
+/// Maximum number of cancellations per abandoned `IouOp` (see `Ring::cancel_head`). Chains are
+/// short, so this is only reached if an operation cannot be cancelled at all (e.g., while it waits
+/// for a stuck file system), in which case it has to complete on its own.
+const MAX_CANCELS: u32 = 16;
+
+/// Maximum number of submissions of a chain (see `read_at`, with timeouts). The ring is sized for
+/// `queue_size` chains in flight at the same time, so that submissions rarely have to wait for
+/// room in the queue (see `Ring::push`). A task may have several chains in flight (see `join`).
+const MAX_OPS_PER_CHAIN: u32 = 5;
+
+/// One or more operations that are submitted together (and possibly linked, see
+/// `IOSQE_IO_LINK`). Resolves to the raw results of all operations (and the resources) once all
+/// of them have completed.
+///
+/// Everything the kernel accesses during the operations (buffers, paths, ...) has to be part of
+/// the `resources`: If the `IouOp` is dropped before completion, its operations are cancelled,
+/// but the resources are only dropped once the kernel is done with them (which the `Executor`
+/// waits for when it is dropped).
+struct IouOp<T: Unpin + 'static> {
+    ring: Handle,
     state: IouOpState,
+    resources: Option<T>,
+    fixed_file: Option<u32>,
 }
 
-impl IouOp {
+impl<T: Unpin + 'static> IouOp<T> {
     /// `op` must be a valid entry (see io_uring docs)
-    unsafe fn new(op: Entry) -> Self {
+    unsafe fn new(ring: &Handle, op: Entry, resources: T) -> Self {
+        Self::chain(ring, vec![op], resources)
+    }
+
+    /// All `ops` must be valid entries (see io_uring docs)
+    unsafe fn chain(ring: &Handle, ops: Vec<Entry>, resources: T) -> Self {
+        assert!(!ops.is_empty() && ops.len() <= MAX_CHAIN_LEN);
         IouOp {
-            state: IouOpState::Inactive(op),
+            ring: ring.clone(),
+            state: IouOpState::Inactive(ops),
+            resources: Some(resources),
+            fixed_file: None,
         }
     }
+
+    /// The chain opens a file into the fixed file `slot` and closes it again in its last
+    /// operation. The slot is returned to the ring once the chain has completed, and the close is
+    /// never cancelled.
+    fn using_fixed_file(mut self, slot: u32) -> Self {
+        self.fixed_file = Some(slot);
+        self
+    }
+}
+
+/// Where the results of the operations of a submitted `IouOp` are collected until all of them
+/// have completed.
+struct OpSlot {
+    results: Vec<Option<i32>>,
+    remaining: usize,
+    waker: Option<Waker>,
+    /// The `IouOp` has been dropped before completion. The slot is freed once the remaining
+    /// operations have completed.
+    abandoned: bool,
+    /// The resources of an abandoned `IouOp`.
+    keep_alive: Option<Box<dyn Any>>,
+    fixed_file: Option<u32>,
+    /// Cancellations (of an abandoned `IouOp`) that have not completed yet. The slot is not
+    /// reused before, so they cannot hit the operations of another `IouOp`.
+    cancels_in_flight: u32,
+    /// Cancellations that have been submitted so far (see `MAX_CANCELS`).
+    cancels: u32,
 }
 
-thread_local! {
-    static CURRENT_TASK_ID: Cell<Option<TaskId>> = Cell::new(None);
-    static CURRENT_RESULT: Cell<Option<i32>> = Cell::new(None);
-    static IO_URING: Cell<*mut io_uring::IoUring> = Cell::new(std::ptr::null_mut());
+/// The io_uring of an executor and the state that operations need to access during submission.
+///
+/// The ring is declared (and thus dropped) first, so registered buffers are never freed while
+/// they are still registered.
+struct Ring {
+    uring: io_uring::IoUring,
+    /// Unused slots of the registered file table (empty if no files could be registered).
+    free_slots: Vec<u32>,
+    fixed_files: bool,
+    buffer_pool: Option<Rc<BufferPool>>,
+    /// Chains that did not fit into the submission queue yet, in submission order.
+    pending: VecDeque<Vec<Entry>>,
+    op_slots: Vec<Option<OpSlot>>,
+    free_op_slots: Vec<usize>,
+    /// Eventfd that wakers signal when called from another thread (see `ReadyQueue`).
+    wake_fd: RawFd,
+    wake_armed: bool,
+    /// For linked timeouts. Never moves, since the ring lives in a `Handle`.
+    timeout: Option<types::Timespec>,
+    /// For opening files (see `open_at`). Never moves, like `timeout`.
+    open_how: types::OpenHow,
+    /// For opening files into fixed file slots (see `open_read_close`). Never moves, like
+    /// `timeout`.
+    open_how_fixed: types::OpenHow,
+    capabilities: Capabilities,
+    /// Number of syscalls made by the executor (see `Executor::syscalls`).
+    syscalls: u64,
+}
+
+impl Ring {
+    /// Queue a chain for submission. If the submission queue is full (or other chains are already
+    /// waiting), the chain is submitted later (see `flush_pending`).
+    ///
+    /// All `ops` must be valid entries (see io_uring docs)
+    unsafe fn push(&mut self, ops: Vec<Entry>) {
+        assert!(
+            ops.len() <= self.uring.params().sq_entries() as usize,
+            "Chain does not fit into the submission queue"
+        );
+        self.pending.push_back(ops);
+        self.flush_pending();
+    }
+
+    /// Move pending chains into the submission queue (in order, and each chain as a whole so that
+    /// a link never points at an unrelated entry). Whenever the queue is full, it is handed over
+    /// to the kernel to make room.
+    fn flush_pending(&mut self) {
+        while let Some(ops) = self.pending.pop_front() {
+            // Safety: Only valid entries are pushed to pending (see `push`).
+            if unsafe { self.uring.submission().push_multiple(&ops) }.is_ok() {
+                continue;
+            }
+            self.submit_and_wait(0);
+            let mut sub = self.uring.submission();
+            sub.sync();
+            let room = sub.capacity() - sub.len();
+            let num_ops = ops.len();
+            self.pending.push_front(ops);
+            if room < num_ops {
+                // The kernel (or its polling thread) has not consumed enough entries yet. Try
+                // again after the next completion.
+                break;
+            }
+        }
+    }
+
+    fn submit_and_wait(&mut self, want: usize) {
+        // With SQPOLL, there is usually no need to enter the kernel unless waiting for
+        // completions or waking up the polling thread.
+        if want > 0
+            || !self.uring.params().is_setup_sqpoll()
+            || self.uring.submission().need_wakeup()
+        {
+            self.syscalls += 1;
+        }
+        match self.uring.submit_and_wait(want) {
+            Ok(_) => {}
+            // The completion queue is full or the kernel is temporarily out of resources: Both
+            // resolve themselves once completions are consumed.
+            Err(e) if e.raw_os_error() == Some(libc::EBUSY) => {}
+            Err(e) if e.raw_os_error() == Some(libc::EAGAIN) => {}
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+            Err(e) => panic!("Failed to submit to io_uring: {}", e),
+        }
+    }
+
+    fn alloc_op_slot(&mut self, num_ops: usize, fixed_file: Option<u32>) -> usize {
+        let slot = OpSlot {
+            results: vec![None; num_ops],
+            remaining: num_ops,
+            waker: None,
+            abandoned: false,
+            keep_alive: None,
+            fixed_file,
+            cancels_in_flight: 0,
+            cancels: 0,
+        };
+        match self.free_op_slots.pop() {
+            Some(index) => {
+                self.op_slots[index] = Some(slot);
+                index
+            }
+            None => {
+                self.op_slots.push(Some(slot));
+                self.op_slots.len() - 1
+            }
+        }
+    }
+
+    /// Returns the results of the operations. Only call once all of them have completed.
+    fn free_op_slot(&mut self, index: usize) -> Vec<i32> {
+        self.free_op_slots.push(index);
+        let slot = self.op_slots[index].take().expect("Freed empty op slot");
+        if let Some(fixed_file) = slot.fixed_file {
+            self.free_slots.push(fixed_file);
+        }
+        slot.results.into_iter().map(Option::unwrap).collect()
+    }
+
+    /// Cancel the first operation that has not completed yet of the abandoned `IouOp` in slot
+    /// `index`. This fails the rest of a linked chain, but operations behind a hard link are
+    /// issued anyway. They are cancelled in turn once the previous cancellation has completed
+    /// (see `complete`). Only one cancellation per slot is in flight at a time.
+    fn cancel_head(&mut self, index: usize) {
+        if !self.capabilities.async_cancel {
+            return;
+        }
+        let slot = match self.op_slots[index].as_mut() {
+            Some(slot) if slot.abandoned => slot,
+            _ => return,
+        };
+        // The close of a fixed file is never cancelled.
+        let num_ops = slot.results.len() - slot.fixed_file.is_some() as usize;
+        let op = match slot.results[..num_ops].iter().position(Option::is_none) {
+            Some(op) => op,
+            None => return,
+        };
+        if slot.cancels_in_flight > 0 || slot.cancels >= MAX_CANCELS {
+            return;
+        }
+        slot.cancels_in_flight += 1;
+        slot.cancels += 1;
+        let user_data = (index as u64) << CHAIN_INDEX_BITS | op as u64;
+        let cancel = AsyncCancel::new(user_data)
+            .build()
+            .user_data(CANCEL_BIT | user_data);
+        // Safety: Cancellations do not access any memory.
+        unsafe { self.push(vec![cancel]) };
+    }
+
+    /// Free the slot of an abandoned `IouOp` once all its operations and cancellations have
+    /// completed, or cancel the next operation.
+    fn continue_abandoned(&mut self, index: usize) {
+        let slot = self.op_slots[index].as_ref().unwrap();
+        if slot.remaining == 0 && slot.cancels_in_flight == 0 {
+            self.free_op_slot(index);
+        } else {
+            self.cancel_head(index);
+        }
+    }
+
+    /// Whether operations of dropped `IouOp`s are still in flight.
+    fn has_abandoned(&self) -> bool {
+        self.op_slots.iter().flatten().any(|slot| slot.abandoned)
+    }
+
+    /// `op` is pushed to `ops`, followed by a linked timeout if the ring has one. The timeout
+    /// cancels `op` when it expires. `link` links `op` to the rest of the chain, and decides
+    /// whether the rest runs anyway if `op` fails (or is cut short, see `IOSQE_IO_HARDLINK`).
+    fn push_with_timeout(&self, ops: &mut Vec<Entry>, op: Entry, link: Flags) {
+        match &self.timeout {
+            Some(timeout) => {
+                // The timeout has to be linked to `op`. A failed `op` severs the chain at its own
+                // link though, so a hard link has to be set on `op` as well.
+                let op_link = if link.is_empty() {
+                    Flags::IO_LINK
+                } else {
+                    link
+                };
+                ops.push(op.flags(op_link));
+                ops.push(LinkTimeout::new(timeout).build().flags(link));
+            }
+            None => ops.push(op.flags(link)),
+        }
+    }
+
+    /// Make sure that a wake from another thread interrupts `submit_and_wait`, by polling the
+    /// wake eventfd.
+    fn arm_wake_poll(&mut self) {
+        // Without poll support, wakes from other threads are only noticed once the next operation
+        // completes.
+        if !self.wake_armed && self.capabilities.poll_add {
+            let op = PollAdd::new(types::Fd(self.wake_fd), libc::POLLIN as _)
+                .build()
+                .user_data(WAKE_USER_DATA);
+            // Safety: wake_fd is valid for as long as the executor exists.
+            unsafe { self.push(vec![op]) };
+            self.wake_armed = true;
+        }
+    }
+
+    /// Submit pending operations, (if `wait`ing and nothing has completed yet, wait for a
+    /// completion) and store the results of all completed operations in their op slots. Tasks
+    /// whose operations have completed are woken. Returns the number of completions.
+    fn complete(&mut self, wait: bool) -> usize {
+        self.flush_pending();
+        if !wait {
+            self.submit_and_wait(0);
+        } else if self.uring.completion().is_empty() {
+            self.arm_wake_poll();
+            self.submit_and_wait(1);
+            self.flush_pending();
+        }
+
+        let mut num_completed = 0;
+        loop {
+            let cqe = match self.uring.completion().next() {
+                Some(cqe) => cqe,
+                None => break,
+            };
+            num_completed += 1;
+            let user_data = cqe.user_data();
+            if user_data & CANCEL_BIT != 0 && user_data != WAKE_USER_DATA {
+                // Whether or not the operation was found (it may not have been issued yet, or
+                // has completed in the meantime), the next one is cancelled, up to
+                // `MAX_CANCELS` times.
+                let index = ((user_data & !CANCEL_BIT) >> CHAIN_INDEX_BITS) as usize;
+                let slot = self.op_slots[index]
+                    .as_mut()
+                    .expect("Cancellation for empty op slot");
+                slot.cancels_in_flight -= 1;
+                self.continue_abandoned(index);
+                continue;
+            }
+            if user_data == WAKE_USER_DATA {
+                let mut counter = 0u64;
+                // Safety: Reads (at most) 8 bytes into counter. Resets the eventfd, failure
+                // (i.e., EAGAIN, if it has been reset before) is fine.
+                unsafe { libc::read(self.wake_fd, &mut counter as *mut u64 as *mut _, 8) };
+                self.syscalls += 1;
+                self.wake_armed = false;
+                continue;
+            }
+            let index = (user_data >> CHAIN_INDEX_BITS) as usize;
+            let op = (user_data & (MAX_CHAIN_LEN as u64 - 1)) as usize;
+            let slot = self.op_slots[index]
+                .as_mut()
+                .expect("Completion for empty op slot");
+            slot.results[op] = Some(cqe.result());
+            slot.remaining -= 1;
+            if slot.abandoned {
+                self.continue_abandoned(index);
+            } else if slot.remaining == 0 {
+                if let Some(waker) = slot.waker.take() {
+                    waker.wake();
+                }
+            }
+        }
+        num_completed
+    }
+}
+
+/// Shared access to the ring of an executor, which is required to submit operations (see
+/// `Executor::handle`).
+#[derive(Clone)]
+pub struct Handle(Rc<RefCell<Ring>>);
+
+impl Handle {
+    fn with_ring<R>(&self, f: impl FnOnce(&mut Ring) -> R) -> R {
+        f(&mut self.0.borrow_mut())
+    }
 }
 
 enum IouOpState {
-    Inactive(Entry),
-    Submitted,
+    Inactive(Vec<Entry>),
+    /// Index of the op slot.
+    Submitted(usize),
     Completed,
 }
 
-impl Future for IouOp {
-    type Output = std::io::Result<i32>;
-    fn poll(self: Pin<&mut Self>, _: &mut Context) -> Poll<Self::Output> {
+impl<T: Unpin + 'static> Future for IouOp<T> {
+    type Output = (Vec<i32>, T);
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
         let this = self.get_mut();
         let mut tmp = IouOpState::Completed;
         std::mem::swap(&mut this.state, &mut tmp);
         match tmp {
-            IouOpState::Inactive(op) => {
-                let op = op.user_data(CURRENT_TASK_ID.with(|i| i.get()).unwrap().0);
-                let uring_ptr = IO_URING.with(|t| t.get());
-                assert!(!uring_ptr.is_null(), "No uring");
-                // Safety: IO_URING is a thread local (=> no send/sync required) and is only ever
-                // set (i.e. != null) in `poll` and `spawn` (which both require a mutable safe
-                // reference.
-                let uring = unsafe { &mut *uring_ptr };
-                let res = {
-                    let mut sub = uring.submission().available();
-                    // Safety: op is safe via new invariant.
-                    unsafe { sub.push(op) }
-                };
-
-                //if uring.submission().len() > 0 {
-                //    let _foo = uring.submit().unwrap();
-                //    //println!("BAR: {}", _foo);
-                //}
-                assert!(res.is_ok(), "Queue is full!");
-                this.state = IouOpState::Submitted;
+            IouOpState::Inactive(ops) => {
+                let fixed_file = this.fixed_file;
+                let index = this.ring.with_ring(|ring| {
+                    let index = ring.alloc_op_slot(ops.len(), fixed_file);
+                    ring.op_slots[index].as_mut().unwrap().waker = Some(cx.waker().clone());
+                    let ops = ops
+                        .into_iter()
+                        .enumerate()
+                        .map(|(i, op)| op.user_data((index as u64) << CHAIN_INDEX_BITS | i as u64))
+                        .collect::<Vec<_>>();
+                    // Safety: ops are safe via new/chain invariant.
+                    unsafe { ring.push(ops) };
+                    index
+                });
+                this.state = IouOpState::Submitted(index);
                 Poll::Pending
             }
-            IouOpState::Submitted => {
-                this.state = IouOpState::Completed;
-                let res = CURRENT_RESULT
-                    .with(|r| r.get())
-                    .expect("Should only be polled when result is ready");
-                Poll::Ready(convert_result(res))
+            IouOpState::Submitted(index) => {
+                let results = this.ring.with_ring(|ring| {
+                    let slot = ring.op_slots[index].as_mut().unwrap();
+                    if slot.remaining == 0 {
+                        return Some(ring.free_op_slot(index));
+                    }
+                    if !slot.waker.as_ref().is_some_and(|w| w.will_wake(cx.waker())) {
+                        slot.waker = Some(cx.waker().clone());
+                    }
+                    None
+                });
+                match results {
+                    Some(results) => Poll::Ready((results, this.resources.take().unwrap())),
+                    None => {
+                        this.state = IouOpState::Submitted(index);
+                        Poll::Pending
+                    }
+                }
             }
             IouOpState::Completed => {
                 panic!("Polling completed IouOp");
@@ -90,6 +452,81 @@ impl Future for IouOp {
     }
 }
 
+impl<T: Unpin + 'static> Drop for IouOp<T> {
+    /// Cancel the operations that are still in flight. The op slot (and the resources) are
+    /// released once all of them have completed.
+    fn drop(&mut self) {
+        let index = match self.state {
+            IouOpState::Submitted(index) => index,
+            _ => return,
+        };
+        let resources = self.resources.take();
+        self.ring.with_ring(|ring| {
+            let slot = ring.op_slots[index].as_mut().unwrap();
+            if slot.remaining == 0 {
+                ring.free_op_slot(index);
+                return;
+            }
+            slot.abandoned = true;
+            slot.waker = None;
+            slot.keep_alive = resources.map(|r| Box::new(r) as Box<dyn Any>);
+            ring.cancel_head(index);
+        });
+    }
+}
+
+/// Run both futures concurrently (within the current task) and return both outputs once both
+/// have completed.
+pub async fn join<A: Future, B: Future>(a: A, b: B) -> (A::Output, B::Output) {
+    let mut a = std::pin::pin!(a);
+    let mut b = std::pin::pin!(b);
+    let mut a_out = None;
+    let mut b_out = None;
+    std::future::poll_fn(|cx| {
+        if a_out.is_none() {
+            if let Poll::Ready(out) = a.as_mut().poll(cx) {
+                a_out = Some(out);
+            }
+        }
+        if b_out.is_none() {
+            if let Poll::Ready(out) = b.as_mut().poll(cx) {
+                b_out = Some(out);
+            }
+        }
+        if a_out.is_some() && b_out.is_some() {
+            Poll::Ready(())
+        } else {
+            Poll::Pending
+        }
+    })
+    .await;
+    (a_out.unwrap(), b_out.unwrap())
+}
+
+/// `op` must be valid as long as `resources` are (see `IouOp`).
+async fn single_op<T: Unpin + 'static>(
+    ring: &Handle,
+    op: Entry,
+    resources: T,
+) -> (std::io::Result<i32>, T) {
+    // Safety: Forwarded to the caller via the safety requirements of IouOp::new.
+    let (results, resources) = unsafe { IouOp::new(ring, op, resources) }.await;
+    (convert_result(results[0]), resources)
+}
+
+/// Like `single_op`, but followed by a linked timeout if the ring has one.
+async fn timed_op<T: Unpin + 'static>(
+    ring: &Handle,
+    op: Entry,
+    resources: T,
+) -> (std::io::Result<i32>, T) {
+    let mut ops = Vec::new();
+    ring.with_ring(|ring| ring.push_with_timeout(&mut ops, op, Flags::empty()));
+    // Safety: Forwarded to the caller, the linked timeout belongs to the ring.
+    let (results, resources) = unsafe { IouOp::chain(ring, ops, resources) }.await;
+    (convert_timed_result(results[0]), resources)
+}
+
 pub struct File {
     inner: std::fs::File,
     offset: usize,
@@ -122,78 +559,356 @@ impl std::ops::DerefMut for File {
     }
 }
 
-pub async fn open(path: &Path) -> std::io::Result<File> {
-    let path = path.as_os_str();
-    let path = CString::new(OsStrExt::as_bytes(path)).unwrap();
+/// Symlinks are followed wherever they point, like in the other backends: Maildirs made of
+/// symlinks to mails in other folders are common (e.g., search results of mu or notmuch).
+fn open_how(flags: i32) -> types::OpenHow {
+    types::OpenHow::new().flags((libc::O_RDONLY | flags) as u64)
+}
 
-    let op = Openat::new(libc::AT_FDCWD, path.as_ref().as_ptr()).build();
+/// Open the file `name` (read only) relative to the directory `dir`.
+pub async fn open_at(ring: &Handle, dir: RawFd, name: &Arc<CStr>) -> std::io::Result<File> {
+    let how = ring.with_ring(|ring| &ring.open_how as *const _);
+    let op = OpenAt2::new(types::Fd(dir), name.as_ptr(), how).build();
 
-    // Safety: There are no safety concerns for the Openat operation.
-    unsafe { IouOp::new(op) }
+    // Safety: `name` is part of the resources and thus outlives the operation, `how` belongs to
+    // the ring.
+    timed_op(ring, op, name.clone())
         .await
-        // Safety: Openat returns a valid fd or an error, but the error case is already handled
-        // in the future implementation of IouOp.
+        .0
+        // Safety: Openat2 returns a valid fd or an error, but the error case is already handled
+        // in convert_result.
         .map(|fd| unsafe { std::fs::File::from_raw_fd(fd).into() })
 }
 
-pub async fn close(file: File) -> std::io::Result<()> {
+pub async fn close(ring: &Handle, file: File) -> std::io::Result<()> {
     let fd = file.inner.into_raw_fd();
-    let op = Close::new(fd).build();
+    let sync_close = ring.with_ring(|ring| {
+        let sync_close = !ring.capabilities.close;
+        ring.syscalls += sync_close as u64;
+        sync_close
+    });
+    if sync_close {
+        // Safety: file is a valid file, so fd is valid as well.
+        return convert_result(unsafe { libc::close(fd) }).map(|_| ());
+    }
+    let op = Close::new(types::Fd(fd)).build();
 
     // Safety: file is a valid file, so fd is valid as well.
-    unsafe { IouOp::new(op) }.await.map(|_| ())
+    single_op(ring, op, ()).await.0.map(|_| ())
+}
+
+/// Reserve `max_to_read` bytes behind the current end of `buf` and return a pointer to them.
+fn reserve_for_read(buf: &mut Vec<u8>, max_to_read: usize) -> *mut u8 {
+    let append_pos = buf.len();
+    buf.reserve(max_to_read);
+    // Safety: We just reserved that space.
+    unsafe { buf.as_mut_ptr().add(append_pos) }
 }
 
 pub async fn read_to_vec(
+    ring: &Handle,
     file: &mut File,
     buf: Vec<u8>,
     max_to_read: usize,
 ) -> std::io::Result<(usize, Vec<u8>)> {
-    let mut buf = std::mem::ManuallyDrop::new(buf);
+    let mut buf = buf;
     let fd = file.inner.as_raw_fd();
     let append_pos = buf.len();
-    let additional_storage = append_pos.saturating_sub(buf.capacity()) + max_to_read;
-    buf.reserve(additional_storage);
-    // Safety: We just reserved that space.
-    let write_pos = unsafe { buf.as_mut_ptr().add(append_pos) };
-    let op = Read::new(Target::Fd(fd), write_pos, max_to_read as _)
+    let write_pos = reserve_for_read(&mut buf, max_to_read);
+    let op = Read::new(types::Fd(fd), write_pos, max_to_read as _)
         .offset(file.offset as _)
         .build();
 
     // Safety:
     // 1. fd corresponds to a valid std::fs::File
-    // 2. buf[write_pos..write_pos+max_to_read] is actually part of the buffer (see calculation of
-    //    `additional_storage` and the following `reserve` call.
-    // 3. If this future is dropped while the IouOp is in flight, buf is not invalidated and merely
-    //    leaked (Not ideal, but still safe. This should never happen in this application anyway.)
-    match unsafe { IouOp::new(op) }.await {
-        Ok(num_written) => {
-            let num_written = num_written as usize;
-            // Safety: We have reserved the space (see above) and we have read the specified number
-            // of additional bytes.
-            unsafe { buf.set_len(append_pos + num_written) };
-            file.offset += num_written;
-            Ok((num_written, std::mem::ManuallyDrop::into_inner(buf)))
+    // 2. buf[write_pos..write_pos+max_to_read] is actually part of the buffer (see
+    //    `reserve_for_read`), which is part of the resources and thus outlives the operation.
+    let (res, mut buf) = timed_op(ring, op, buf).await;
+    let num_written = res? as usize;
+    // Safety: We have reserved the space (see above) and we have read the specified number of
+    // additional bytes.
+    unsafe { buf.set_len(append_pos + num_written) };
+    file.offset += num_written;
+    Ok((num_written, buf))
+}
+
+/// The chain open `name` relative to `dir` into the fixed file `slot` -> `read` -> close, where
+/// the open and the read are followed by linked timeouts if the ring has one.
+///
+/// `read` has to read from `slot`. Returns the chain and the result indices of the read and the
+/// close (the open is always the first operation).
+fn open_read_close(
+    ring: &Handle,
+    dir: RawFd,
+    name: &CStr,
+    slot: u32,
+    read: Entry,
+) -> (Vec<Entry>, usize, usize) {
+    let destination = types::DestinationSlot::try_from_slot_target(slot).unwrap();
+    let mut ops = Vec::new();
+    ring.with_ring(|ring| {
+        let open = OpenAt2::new(types::Fd(dir), name.as_ptr(), &ring.open_how_fixed)
+            .file_index(Some(destination))
+            .build();
+        ring.push_with_timeout(&mut ops, open, Flags::IO_LINK);
+        let read_index = ops.len();
+        // A short read severs a normal link, but the file has to be closed in any case.
+        ring.push_with_timeout(&mut ops, read, Flags::IO_HARDLINK);
+        ops.push(Close::new(types::Fixed(slot)).build());
+        let close_index = ops.len() - 1;
+        (ops, read_index, close_index)
+    })
+}
+
+/// Open `name` relative to `dir` (see `open_at`), append up to `max_to_read` bytes starting
+/// at `offset` to `buf` and close the file again.
+///
+/// If the executor supports fixed files, this is submitted as a single linked chain
+/// (open into a fixed file slot -> read -> close) and thus only takes one round trip. Otherwise
+/// the three operations are submitted one after another.
+pub async fn read_at(
+    ring: &Handle,
+    dir: RawFd,
+    name: &Arc<CStr>,
+    buf: Vec<u8>,
+    offset: usize,
+    max_to_read: usize,
+) -> std::io::Result<(usize, Vec<u8>)> {
+    let slot = ring.with_ring(|ring| ring.free_slots.pop());
+    let slot = match slot {
+        Some(slot) => slot,
+        None => {
+            let mut file = open_at(ring, dir, name).await?;
+            file.offset = offset;
+            let res = read_to_vec(ring, &mut file, buf, max_to_read).await;
+            close(ring, file).await?;
+            return res;
         }
-        Err(e) => Err(e),
+    };
+
+    let mut buf = buf;
+    let append_pos = buf.len();
+    let write_pos = reserve_for_read(&mut buf, max_to_read);
+    let read = Read::new(types::Fixed(slot), write_pos, max_to_read as _)
+        .offset(offset as _)
+        .build();
+    let (ops, read_index, close_index) = open_read_close(ring, dir, name, slot, read);
+
+    // Safety:
+    // 1. `name` and `buf` are part of the resources and thus outlive the operations. The open
+    //    flags belong to the ring.
+    // 2. The read targets the slot that the (linked, i.e. preceding) open installs the file in.
+    // 3. The read target is part of buf (see `read_to_vec`).
+    let (results, (mut buf, _)) =
+        unsafe { IouOp::chain(ring, ops, (buf, name.clone())).using_fixed_file(slot) }.await;
+
+    let num_written = convert_timed_result(results[0])
+        .and_then(|_| convert_timed_result(results[read_index]))
+        .map(|n| n as usize)?;
+    // Safety: We have reserved the space (see above) and we have read the specified number of
+    // additional bytes.
+    unsafe { buf.set_len(append_pos + num_written) };
+    convert_result(results[close_index])?;
+    Ok((num_written, buf))
+}
+
+/// Equally sized buffers that are registered with the ring (see `IORING_REGISTER_BUFFERS`), so
+/// that the kernel does not have to map the destination of every read separately.
+pub struct BufferPool {
+    memory: *mut u8,
+    num_buffers: usize,
+    buffer_size: usize,
+    free: RefCell<Vec<u16>>,
+}
+
+impl BufferPool {
+    fn new(num_buffers: u16, buffer_size: usize) -> Self {
+        let memory = vec![0u8; num_buffers as usize * buffer_size].into_boxed_slice();
+        BufferPool {
+            memory: Box::into_raw(memory) as *mut u8,
+            num_buffers: num_buffers as usize,
+            buffer_size,
+            free: RefCell::new((0..num_buffers).collect()),
+        }
+    }
+
+    fn iovecs(&self) -> Vec<libc::iovec> {
+        (0..self.num_buffers)
+            .map(|i| libc::iovec {
+                // Safety: All buffers are part of memory.
+                iov_base: unsafe { self.memory.add(i * self.buffer_size) } as _,
+                iov_len: self.buffer_size,
+            })
+            .collect()
+    }
+
+    fn take(self: &Rc<Self>) -> Option<FixedBuffer> {
+        let index = self.free.borrow_mut().pop()?;
+        Some(FixedBuffer {
+            pool: self.clone(),
+            index,
+            len: 0,
+        })
+    }
+}
+
+impl Drop for BufferPool {
+    fn drop(&mut self) {
+        let len = self.num_buffers * self.buffer_size;
+        // Safety: memory was obtained from a boxed slice of that length in `new`.
+        drop(unsafe { Box::from_raw(std::ptr::slice_from_raw_parts_mut(self.memory, len)) });
     }
 }
 
-fn dummy_raw_waker() -> RawWaker {
-    fn no_op(_: *const ()) {}
-    fn clone(_: *const ()) -> RawWaker {
-        dummy_raw_waker()
+/// A buffer of a `BufferPool`, which is returned to the pool when dropped. Derefs to the bytes
+/// that have been read into it.
+pub struct FixedBuffer {
+    pool: Rc<BufferPool>,
+    index: u16,
+    len: usize,
+}
+
+impl FixedBuffer {
+    fn as_mut_ptr(&mut self) -> *mut u8 {
+        // Safety: index < num_buffers, so the buffer is part of memory.
+        unsafe {
+            self.pool
+                .memory
+                .add(self.index as usize * self.pool.buffer_size)
+        }
     }
 
-    let vtable = &RawWakerVTable::new(clone, no_op, no_op, no_op);
-    RawWaker::new(0 as *const (), vtable)
+    fn capacity(&self) -> usize {
+        self.pool.buffer_size
+    }
 }
 
-fn dummy_waker() -> Waker {
-    let raw = dummy_raw_waker();
-    // Safety: The dummy waker literally does nothing and thus upholds all cantracts of
-    // `RawWaker`/`RawWakerVTable`.
-    unsafe { Waker::from_raw(raw) }
+impl std::ops::Deref for FixedBuffer {
+    type Target = [u8];
+
+    fn deref(&self) -> &Self::Target {
+        // Safety: The buffer is part of memory and owned exclusively by this FixedBuffer (i.e.,
+        // the kernel does not write into it anymore) and the first len bytes have been read.
+        unsafe {
+            std::slice::from_raw_parts(
+                self.pool
+                    .memory
+                    .add(self.index as usize * self.pool.buffer_size),
+                self.len,
+            )
+        }
+    }
+}
+
+impl Drop for FixedBuffer {
+    fn drop(&mut self) {
+        self.pool.free.borrow_mut().push(self.index);
+    }
+}
+
+/// Like `read_at`, but read (at most the buffer size of the pool) into a registered buffer.
+/// Returns `None` (without reading anything) if the executor does not support fixed files and
+/// buffers or if all buffers are in use.
+pub async fn read_at_fixed(
+    ring: &Handle,
+    dir: RawFd,
+    name: &Arc<CStr>,
+    offset: usize,
+) -> std::io::Result<Option<FixedBuffer>> {
+    let resources = ring.with_ring(|ring| {
+        let buf = ring.buffer_pool.as_ref()?.take()?;
+        let slot = ring.free_slots.pop()?;
+        Some((slot, buf))
+    });
+    let (slot, mut buf) = match resources {
+        Some(resources) => resources,
+        None => return Ok(None),
+    };
+
+    let read = ReadFixed::new(
+        types::Fixed(slot),
+        buf.as_mut_ptr(),
+        buf.capacity() as _,
+        buf.index,
+    )
+    .offset(offset as _)
+    .build();
+    let (ops, read_index, close_index) = open_read_close(ring, dir, name, slot, read);
+
+    // Safety:
+    // 1. `name` and the open flags outlive the operations (see `read_at`).
+    // 2. The read targets the slot that the (linked, i.e. preceding) open installs the file in.
+    // 3. The read targets the registered buffer with the given index, which is owned by `buf`
+    //    and thus not used by anybody else until the read has completed (`buf` is part of the
+    //    resources).
+    let (results, (mut buf, _)) =
+        unsafe { IouOp::chain(ring, ops, (buf, name.clone())).using_fixed_file(slot) }.await;
+
+    convert_timed_result(results[0])?;
+    buf.len = convert_timed_result(results[read_index])? as usize;
+    convert_result(results[close_index])?;
+    Ok(Some(buf))
+}
+
+/// The ids of the tasks that have been woken and have to be polled again.
+///
+/// Wakers may be called from other threads (e.g., by a channel that a task waits for). In that
+/// case the executor may be blocked waiting for a completion, so the wake eventfd is signaled,
+/// which completes a poll in the ring (see `Ring::arm_wake_poll`).
+struct ReadyQueue {
+    tasks: Mutex<VecDeque<TaskId>>,
+    executor_thread: ThreadId,
+    wake_fd: RawFd,
+}
+
+impl ReadyQueue {
+    fn new() -> std::io::Result<Self> {
+        // Safety: eventfd has no memory safety requirements.
+        let wake_fd = unsafe { libc::eventfd(0, libc::EFD_CLOEXEC | libc::EFD_NONBLOCK) };
+        if wake_fd < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        Ok(ReadyQueue {
+            tasks: Mutex::new(VecDeque::new()),
+            executor_thread: std::thread::current().id(),
+            wake_fd,
+        })
+    }
+
+    fn push(&self, task_id: TaskId) {
+        self.tasks.lock().unwrap().push_back(task_id);
+        if std::thread::current().id() != self.executor_thread {
+            let one = 1u64;
+            // Safety: Writes 8 bytes from one to an eventfd.
+            unsafe { libc::write(self.wake_fd, &one as *const u64 as *const _, 8) };
+        }
+    }
+
+    fn pop(&self) -> Option<TaskId> {
+        self.tasks.lock().unwrap().pop_front()
+    }
+}
+
+impl Drop for ReadyQueue {
+    fn drop(&mut self) {
+        // Safety: wake_fd was opened in `new` and is not used anymore.
+        unsafe { libc::close(self.wake_fd) };
+    }
+}
+
+struct TaskWaker {
+    task_id: TaskId,
+    ready: Arc<ReadyQueue>,
+}
+
+impl Wake for TaskWaker {
+    fn wake(self: Arc<Self>) {
+        self.wake_by_ref();
+    }
+
+    fn wake_by_ref(self: &Arc<Self>) {
+        self.ready.push(self.task_id);
+    }
 }
 
 #[derive(Copy, Clone, Hash, PartialEq, Debug, Eq)]
@@ -209,156 +924,354 @@ impl TaskId {
 struct Task<'future> {
     id: TaskId,
     future: Pin<Box<dyn Future<Output = ()> + 'future>>,
+    waker: Waker,
 }
 
 impl<'future> Task<'future> {
-    fn new(f: impl Future<Output = ()> + 'future) -> Task<'future> {
+    fn new(f: impl Future<Output = ()> + 'future, ready: &Arc<ReadyQueue>) -> Task<'future> {
+        let id = TaskId::new();
         Task {
-            id: TaskId::new(),
+            id,
             future: Box::pin(f),
+            waker: Arc::new(TaskWaker {
+                task_id: id,
+                ready: ready.clone(),
+            })
+            .into(),
         }
     }
 
     fn id(&self) -> TaskId {
         self.id
     }
+
+    fn poll(&mut self) -> Poll<()> {
+        let mut context = Context::from_waker(&self.waker);
+        self.future.as_mut().poll(&mut context)
+    }
 }
 
-pub struct Executor<'tasks> {
-    tasks: HashMap<TaskId, Task<'tasks>>,
-    uring: io_uring::IoUring,
-    waker: Waker,
-    queue_size: usize,
+/// The operations and features of io_uring that the executor uses (see
+/// `IORING_REGISTER_PROBE`). Only `openat2` and `read` are strictly required, the executor
+/// adapts to the absence of the others.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct Capabilities {
+    pub openat2: bool,
+    pub read: bool,
+    /// Reads into registered buffers (see `read_at_fixed`).
+    pub read_fixed: bool,
+    /// Otherwise files are closed synchronously.
+    pub close: bool,
+    /// Cancellation of dropped operations (see `IouOp::drop`).
+    pub async_cancel: bool,
+    /// Timeouts (see `ExecutorBuilder::timeout`).
+    pub link_timeout: bool,
+    /// Wakes from other threads while waiting for completions (see `Ring::arm_wake_poll`).
+    pub poll_add: bool,
+    /// A file that is opened into a fixed file slot can be used by the following operations of
+    /// the same chain (`IORING_FEAT_LINKED_FILE`), which is required for `read_at`'s
+    /// linked chains.
+    pub linked_file: bool,
+    /// No completions are lost if the completion queue overflows (`IORING_FEAT_NODROP`).
+    pub nodrop: bool,
+    /// Reads from pipes and sockets are polled instead of blocking a worker thread
+    /// (`IORING_FEAT_FAST_POLL`).
+    pub fast_poll: bool,
 }
 
-impl<'tasks> Executor<'tasks> {
-    pub fn fully_supported(self) -> std::io::Result<Self> {
-        async fn test_run() -> std::io::Result<()> {
-            let path = Path::new("/dev/null");
-            let mut file = open(path).await?;
-            let _ = read_to_vec(&mut file, Vec::new(), 1).await?;
-            close(file).await?;
-            Ok(())
-        }
-        let mut res = Ok(());
-        let mut executor = self;
-        let executor = {
-            executor.spawn(async {
-                if let Err(e) = test_run().await {
-                    res = Err(e);
-                }
-            });
-            executor.run_to_completion()
-        };
-        res.map(|_| executor)
+impl Capabilities {
+    fn probe(uring: &io_uring::IoUring) -> std::io::Result<Self> {
+        let mut probe = Probe::new();
+        uring.submitter().register_probe(&mut probe)?;
+        let params = uring.params();
+        Ok(Capabilities {
+            openat2: probe.is_supported(OpenAt2::CODE),
+            read: probe.is_supported(Read::CODE),
+            read_fixed: probe.is_supported(ReadFixed::CODE),
+            close: probe.is_supported(Close::CODE),
+            async_cancel: probe.is_supported(AsyncCancel::CODE),
+            link_timeout: probe.is_supported(LinkTimeout::CODE),
+            poll_add: probe.is_supported(PollAdd::CODE),
+            linked_file: params.is_feature_linked_file(),
+            nodrop: params.is_feature_nodrop(),
+            fast_poll: params.is_feature_fast_poll(),
+        })
+    }
+
+    /// All capabilities by name.
+    pub fn list(&self) -> [(&'static str, bool); 10] {
+        [
+            ("openat2", self.openat2),
+            ("read", self.read),
+            ("read_fixed", self.read_fixed),
+            ("close", self.close),
+            ("async_cancel", self.async_cancel),
+            ("link_timeout", self.link_timeout),
+            ("poll_add", self.poll_add),
+            ("linked_file", self.linked_file),
+            ("nodrop", self.nodrop),
+            ("fast_poll", self.fast_poll),
+        ]
     }
 
-    pub fn run_to_completion<'a>(mut self) -> Executor<'a> {
-        while self.has_tasks() {
-            self.poll(true);
+    /// The names of the missing operations that the executor cannot do without.
+    pub fn missing_required(&self) -> Vec<&'static str> {
+        let mut missing = Vec::new();
+        if !self.openat2 {
+            missing.push("openat2");
         }
-        Executor {
-            tasks: HashMap::new(),
-            uring: self.uring,
-            waker: self.waker,
-            queue_size: self.queue_size,
+        if !self.read {
+            missing.push("read");
         }
+        missing
     }
+}
 
-    pub fn new(queue_size: u32) -> Self {
-        Executor {
-            tasks: HashMap::new(),
-            uring: io_uring::IoUring::new(queue_size).unwrap(),
-            waker: dummy_waker(),
-            queue_size: queue_size as _,
+/// Options for creating an `Executor`.
+#[derive(Copy, Clone, Debug)]
+pub struct ExecutorBuilder {
+    queue_size: u32,
+    sqpoll_idle_ms: Option<u32>,
+    sqpoll_cpu: Option<u32>,
+    fixed_files: bool,
+    timeout: Option<Duration>,
+}
+
+impl Default for ExecutorBuilder {
+    fn default() -> Self {
+        ExecutorBuilder {
+            queue_size: 1 << 6,
+            sqpoll_idle_ms: None,
+            sqpoll_cpu: None,
+            fixed_files: true,
+            timeout: None,
         }
     }
+}
 
-    pub fn max_tasks(&self) -> usize {
-        self.queue_size
+impl ExecutorBuilder {
+    /// Maximum number of concurrent operations (or chains of operations), e.g. the number of tasks
+    /// if each of them only has one chain in flight at a time.
+    pub fn queue_size(mut self, queue_size: u32) -> Self {
+        self.queue_size = queue_size;
+        self
     }
 
-    pub fn num_tasks(&self) -> usize {
-        self.tasks.len()
+    /// Let a kernel thread poll the submission queue, which goes to sleep after `idle_ms`
+    /// milliseconds without submissions.
+    pub fn sqpoll(mut self, idle_ms: u32) -> Self {
+        self.sqpoll_idle_ms = Some(idle_ms);
+        self
     }
 
-    pub fn spawn(&mut self, f: impl Future<Output = ()> + 'tasks) {
-        let mut task = Task::new(f);
+    /// Pin the polling thread (see `sqpoll`) to a CPU.
+    pub fn sqpoll_cpu(mut self, cpu: u32) -> Self {
+        self.sqpoll_cpu = Some(cpu);
+        self
+    }
 
-        let task_id = task.id();
+    /// Whether to try to register a file table (see `Executor::fixed_files`).
+    pub fn fixed_files(mut self, fixed_files: bool) -> Self {
+        self.fixed_files = fixed_files;
+        self
+    }
 
-        CURRENT_TASK_ID.with(|r| r.set(Some(task_id)));
-        let uring = &mut self.uring;
-        IO_URING.with(|t| t.set(uring as _));
+    /// Cancel opening and reading files if they take longer than `timeout` (each), e.g. on a
+    /// stuck network file system. Such reads fail with `ErrorKind::TimedOut`.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
 
-        let mut context = Context::from_waker(&self.waker);
-        match task.future.as_mut().poll(&mut context) {
-            Poll::Pending => {}
-            Poll::Ready(_) => return,
+    pub fn build<'a>(self) -> std::io::Result<Executor<'a>> {
+        let mut builder = io_uring::IoUring::builder();
+        if let Some(idle_ms) = self.sqpoll_idle_ms {
+            builder.setup_sqpoll(idle_ms);
+            if let Some(cpu) = self.sqpoll_cpu {
+                builder.setup_sqpoll_cpu(cpu);
+            }
         }
+        let uring = builder.build(self.queue_size * MAX_OPS_PER_CHAIN)?;
+        let capabilities = Capabilities::probe(&uring)?;
+        // Every task needs at most one slot at a time. -1 marks an empty slot.
+        let fixed_files = self.fixed_files
+            && capabilities.openat2
+            && capabilities.close
+            && capabilities.linked_file
+            && uring
+                .submitter()
+                .register_files(&vec![-1; self.queue_size as usize])
+                .is_ok();
+        let free_slots = if fixed_files {
+            (0..self.queue_size).collect()
+        } else {
+            Vec::new()
+        };
+        let ready = Arc::new(ReadyQueue::new()?);
+        let ring = Ring {
+            uring,
+            free_slots,
+            fixed_files,
+            buffer_pool: None,
+            pending: VecDeque::new(),
+            op_slots: Vec::new(),
+            free_op_slots: Vec::new(),
+            wake_fd: ready.wake_fd,
+            wake_armed: false,
+            timeout: self
+                .timeout
+                .filter(|_| capabilities.link_timeout)
+                .map(types::Timespec::from),
+            open_how: open_how(libc::O_CLOEXEC),
+            // Direct descriptors are never inherited, O_CLOEXEC is rejected for them.
+            open_how_fixed: open_how(0),
+            capabilities,
+            syscalls: 0,
+        };
+        Ok(Executor {
+            tasks: HashMap::new(),
+            handle: Handle(Rc::new(RefCell::new(ring))),
+            ready,
+            queue_size: self.queue_size as _,
+        })
+    }
+}
 
-        IO_URING.with(|t| t.set(std::ptr::null_mut()));
+pub struct Executor<'tasks> {
+    tasks: HashMap<TaskId, Task<'tasks>>,
+    handle: Handle,
+    ready: Arc<ReadyQueue>,
+    queue_size: usize,
+}
 
-        let prev = self.tasks.insert(task_id, task);
-        assert!(prev.is_none(), "Id somehow reused");
+impl Drop for Executor<'_> {
+    /// Closing the ring does not wait for operations that are still in flight (e.g., a read that
+    /// is stuck on a network file system), which may still write into their resources or the
+    /// registered buffers. So the operations of all tasks are cancelled and waited for. This
+    /// blocks for as long as an operation can neither be cancelled nor completes.
+    fn drop(&mut self) {
+        // Dropping the tasks abandons their operations.
+        self.tasks.clear();
+        self.handle.with_ring(|ring| {
+            while ring.has_abandoned() {
+                ring.complete(true);
+            }
+        });
     }
+}
 
-    fn next_result(&mut self, wait: bool) -> Option<(TaskId, i32)> {
-        let result = loop {
-            if !wait {
-                let _foo = self.uring.submit().unwrap();
-                //println!("Not wait: {}", _foo);
-            }
-            {
-                if let Some(res) = self.uring.completion().available().into_iter().next() {
-                    break res;
-                }
+impl<'tasks> Executor<'tasks> {
+    /// Fails if the kernel lacks operations that are required (see `Capabilities`).
+    pub fn fully_supported(self) -> std::io::Result<Self> {
+        let missing = self.capabilities().missing_required();
+        if missing.is_empty() {
+            Ok(self)
+        } else {
+            Err(std::io::Error::new(
+                std::io::ErrorKind::Unsupported,
+                format!("missing io_uring operations: {}", missing.join(", ")),
+            ))
+        }
+    }
+
+    pub fn builder() -> ExecutorBuilder {
+        ExecutorBuilder::default()
+    }
+
+    /// For submitting operations from the tasks of this executor.
+    pub fn handle(&self) -> Handle {
+        self.handle.clone()
+    }
+
+    /// Whether linked open/read/close chains using fixed file slots are used.
+    pub fn fixed_files(&self) -> bool {
+        self.handle.0.borrow().fixed_files
+    }
+
+    pub fn capabilities(&self) -> Capabilities {
+        self.handle.0.borrow().capabilities
+    }
+
+    /// Number of syscalls made so far (approximate with SQPOLL), excluding the setup of the ring.
+    pub fn syscalls(&self) -> u64 {
+        self.handle.0.borrow().syscalls
+    }
+
+    /// Whether a buffer pool is registered (see `register_buffer_pool`).
+    pub fn registered_buffers(&self) -> bool {
+        self.handle.0.borrow().buffer_pool.is_some()
+    }
+
+    /// Register `num_buffers` buffers of `buffer_size` bytes with the ring, which are then used
+    /// by `read_at_fixed`. Must not be called while tasks are running.
+    pub fn register_buffer_pool(
+        &mut self,
+        num_buffers: u16,
+        buffer_size: usize,
+    ) -> std::io::Result<()> {
+        assert!(!self.has_tasks());
+        self.handle.with_ring(|ring| {
+            if !ring.capabilities.read_fixed {
+                return Err(std::io::ErrorKind::Unsupported.into());
             }
-            if !wait {
-                //println!("Not ready");
-                return None;
+            let submitter = ring.uring.submitter();
+            if ring.buffer_pool.take().is_some() {
+                submitter.unregister_buffers()?;
             }
-            let _foo = self.uring.submit_and_wait(1).unwrap(); //TODO figure out where to submit best
-                                                               //println!("Wait: {}", _foo);
-            break self
-                .uring
-                .completion()
-                .available()
-                .into_iter()
-                .next()
-                .unwrap();
-        };
-        let id = result.user_data();
-        let task_result = result.result();
-        Some((TaskId(id), task_result))
+            let pool = BufferPool::new(num_buffers, buffer_size);
+            // Safety: The pool (and thus all buffers) lives at least as long as the ring, see
+            // Ring.
+            unsafe { submitter.register_buffers(&pool.iovecs())? };
+            ring.buffer_pool = Some(Rc::new(pool));
+            Ok(())
+        })
     }
 
-    pub fn poll(&mut self, wait: bool) -> ExecutorPollResult {
-        let (task_id, result) = if let Some(r) = self.next_result(wait) {
-            r
-        } else {
-            return ExecutorPollResult::WouldBlock;
-        };
-        let uring = &mut self.uring;
-        let task = self.tasks.get_mut(&task_id).expect("Invalid task id");
+    /// Maximum number of concurrent chains (see `ExecutorBuilder::queue_size`).
+    pub fn max_chains(&self) -> usize {
+        self.queue_size
+    }
 
-        CURRENT_RESULT.with(|r| r.set(Some(result)));
-        CURRENT_TASK_ID.with(|r| r.set(Some(task_id)));
-        IO_URING.with(|t| t.set(uring as _));
+    pub fn num_tasks(&self) -> usize {
+        self.tasks.len()
+    }
 
-        //eprintln!("Running id {}", task_id.0);
+    /// Spawn a task, which is polled for the first time right away.
+    pub fn spawn(&mut self, f: impl Future<Output = ()> + 'tasks) {
+        let mut task = Task::new(f, &self.ready);
+        if task.poll().is_ready() {
+            return;
+        }
+        let prev = self.tasks.insert(task.id(), task);
+        assert!(prev.is_none(), "Id somehow reused");
+    }
 
-        let mut context = Context::from_waker(&self.waker);
-        let res = match task.future.as_mut().poll(&mut context) {
-            Poll::Pending => ExecutorPollResult::Polled,
-            Poll::Ready(_) => {
-                self.tasks.remove(&task_id).expect("Invalid task_id");
-                ExecutorPollResult::Finished
+    /// Poll the next task that has been woken. If there is none, wait (if `wait`) for the
+    /// completion of an operation (or a wake from another thread).
+    pub fn poll(&mut self, wait: bool) -> ExecutorPollResult {
+        loop {
+            while let Some(task_id) = self.ready.pop() {
+                // Tasks may be woken after they have finished.
+                let task = match self.tasks.get_mut(&task_id) {
+                    Some(task) => task,
+                    None => continue,
+                };
+                return match task.poll() {
+                    Poll::Pending => ExecutorPollResult::Polled,
+                    Poll::Ready(_) => {
+                        self.tasks.remove(&task_id);
+                        ExecutorPollResult::Finished
+                    }
+                };
             }
-        };
-
-        IO_URING.with(|t| t.set(std::ptr::null_mut()));
-        res
+            if !self.has_tasks() {
+                return ExecutorPollResult::WouldBlock;
+            }
+            let num_completed = self.handle.with_ring(|ring| ring.complete(wait));
+            if num_completed == 0 && !wait {
+                return ExecutorPollResult::WouldBlock;
+            }
+        }
     }
 
     pub fn has_tasks(&self) -> bool {
@@ -371,3 +1284,155 @@ pub enum ExecutorPollResult {
     Polled,
     Finished,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::ffi::CString;
+    use std::io::Write;
+    use std::os::unix::ffi::OsStringExt;
+    use std::path::PathBuf;
+
+    /// Report that `test` is skipped. Written to stderr directly, since the test harness only
+    /// shows what is printed (with `eprintln!`) by failing tests.
+    fn skip(test: &str, reason: &str) {
+        let _ = writeln!(std::io::stderr(), "skipping {}: {}", test, reason);
+    }
+
+    /// An executor from `builder`, or `None` (and `test` is skipped) if io_uring is not supported
+    /// in this environment.
+    fn executor(test: &str, builder: ExecutorBuilder) -> Option<Executor<'static>> {
+        match builder.build().and_then(Executor::fully_supported) {
+            Ok(executor) => Some(executor),
+            Err(e) => {
+                skip(test, &format!("io_uring is not supported ({})", e));
+                None
+            }
+        }
+    }
+
+    /// A fresh directory with a single file `name` that contains `contents`.
+    fn mail_dir(test: &str, name: &str, contents: &[u8]) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("maq-{}-{}", test, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join(name), contents).unwrap();
+        dir
+    }
+
+    /// Run `f` as the only task of `executor` until it has completed.
+    fn block_on<R: 'static>(
+        executor: &mut Executor,
+        f: impl Future<Output = R> + 'static,
+    ) -> Option<R> {
+        let output = Rc::new(RefCell::new(None));
+        let task_output = output.clone();
+        executor.spawn(async move {
+            *task_output.borrow_mut() = Some(f.await);
+        });
+        while executor.has_tasks() {
+            executor.poll(true);
+        }
+        output.take()
+    }
+
+    #[test]
+    fn timed_short_reads_close_the_file() {
+        let builder = Executor::builder().timeout(Duration::from_secs(5));
+        let mut executor = match executor("timed_short_reads_close_the_file", builder) {
+            Some(executor) => executor,
+            None => return,
+        };
+        let contents = b"From: a@b.c\n\nshort";
+        let dir = mail_dir("short-read", "mail", contents);
+        let dir_fd = std::fs::File::open(&dir).unwrap();
+        let name = Arc::<CStr>::from(CString::new("mail").unwrap());
+        let _ = executor.register_buffer_pool(1, 4096);
+        let ring = executor.handle();
+        let num_slots = ring.with_ring(|ring| ring.free_slots.len());
+
+        let (fd, task_ring, task_name) = (dir_fd.as_raw_fd(), ring.clone(), name.clone());
+        let read = block_on(&mut executor, async move {
+            read_at(&task_ring, fd, &task_name, Vec::new(), 0, 4096).await
+        });
+        let (num_read, buf) = read.unwrap().unwrap();
+        assert_eq!(num_read, contents.len());
+        assert_eq!(buf, contents);
+
+        let (task_ring, task_name) = (ring.clone(), name.clone());
+        let read = block_on(&mut executor, async move {
+            read_at_fixed(&task_ring, fd, &task_name, 0)
+                .await
+                .map(|buf| buf.map(|buf| buf.to_vec()))
+        });
+        if let Some(buf) = read.unwrap().unwrap() {
+            assert_eq!(buf, contents);
+        }
+
+        // Both chains have closed their file and returned the slot.
+        assert_eq!(ring.with_ring(|ring| ring.free_slots.len()), num_slots);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn joined_reads_return_both_contents() {
+        let mut executor = match executor("joined_reads_return_both_contents", Executor::builder())
+        {
+            Some(executor) => executor,
+            None => return,
+        };
+        let dir = mail_dir("join", "a", b"From: a@b.c\n\n");
+        std::fs::write(dir.join("b"), b"From: d@e.f\n\nlonger").unwrap();
+        let dir_fd = std::fs::File::open(&dir).unwrap();
+        let ring = executor.handle();
+        let fd = dir_fd.as_raw_fd();
+        let reads = block_on(&mut executor, async move {
+            let a = CString::new("a").unwrap().into();
+            let b = CString::new("b").unwrap().into();
+            let (a, b) = join(
+                read_at(&ring, fd, &a, Vec::new(), 0, 4096),
+                read_at(&ring, fd, &b, Vec::new(), 0, 4096),
+            )
+            .await;
+            (a.unwrap().1, b.unwrap().1)
+        });
+        assert_eq!(
+            reads.unwrap(),
+            (
+                b"From: a@b.c\n\n".to_vec(),
+                b"From: d@e.f\n\nlonger".to_vec()
+            )
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn dropping_the_executor_waits_for_abandoned_reads() {
+        let test = "dropping_the_executor_waits_for_abandoned_reads";
+        let mut executor = match executor(test, Executor::builder()) {
+            Some(executor) if executor.capabilities().async_cancel => executor,
+            Some(_) => return skip(test, "IORING_OP_ASYNC_CANCEL is not supported"),
+            None => return,
+        };
+        let dir = mail_dir("abandoned", "placeholder", b"");
+        let fifo = CString::new(dir.join("fifo").into_os_string().into_vec()).unwrap();
+        // Safety: fifo is nul terminated.
+        assert_eq!(unsafe { libc::mkfifo(fifo.as_ptr(), 0o600) }, 0);
+        let dir_fd = std::fs::File::open(&dir).unwrap();
+        let ring = executor.handle();
+        let fd = dir_fd.as_raw_fd();
+        // Opening a fifo blocks until there is a writer, which never comes.
+        executor.spawn(async move {
+            let name = CString::new("fifo").unwrap().into();
+            let _ = read_at(&ring, fd, &name, Vec::new(), 0, 4096).await;
+            unreachable!("The fifo has no writer");
+        });
+        executor.poll(false);
+        assert!(executor.has_tasks());
+        let handle = executor.handle();
+        // Only returns once the open has been cancelled.
+        std::mem::drop(executor);
+        assert!(!handle.with_ring(|ring| ring.has_abandoned()));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
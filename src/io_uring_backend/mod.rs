@@ -1,119 +1,224 @@
-use crate::common::{process_mail_header, AddrCollection, HeaderParseResult, Mails};
-use crate::{Backend, Matcher};
+use crate::common::{
+    scan_threads, AddrCollection, Collect, HeaderParseResult, HeaderReader, Mail, Mails, Progress,
+    FIRST_BLOCK_SIZE,
+};
+use crate::{Backend, Matcher, ScanOptions};
 use core::cell::RefCell;
-use std::path::{Path, PathBuf};
+use std::path::PathBuf;
 
 mod executor;
 
-use executor::{close, open, read_to_vec, Executor, ExecutorPollResult};
+use executor::{
+    join, read_at, read_at_fixed, Capabilities, Executor, ExecutorBuilder, ExecutorPollResult,
+    Handle,
+};
+
+/// Every task processes this many mails concurrently, which saves allocating and polling tasks.
+/// The number of tasks is reduced accordingly, so that there are at most as many mails in flight
+/// as the executor has room for (see `Executor::max_chains`).
+const MAILS_PER_TASK: usize = 2;
 
 pub struct IoUringBackend<'a> {
+    /// Tells which features are supported. The scan itself uses executors from
+    /// `executor_builder`, see `run`.
     main_executor: Executor<'a>,
+    /// For the executors of all threads.
+    executor_builder: ExecutorBuilder,
+    threads: usize,
+    inode_order: bool,
+    collect: Collect,
 }
 
 async fn process_mail(
-    path: &Path,
+    ring: &Handle,
+    m: &Mail,
     matcher: &impl Matcher,
     addr_collection: &RefCell<AddrCollection>,
 ) -> std::io::Result<()> {
-    let mut file = open(path).await?;
+    let mut reader = HeaderReader::new(m.info());
 
-    let read_block_size = 4 * 1024; //4KB
-    let mut buf = Vec::new();
+    // Most headers fit into the first block, which is read in a single linked
+    // open -> read -> close submission (into a registered buffer if possible, in which case it is
+    // processed without copying it). Longer headers are read in larger follow-up blocks.
+    if let Some(block) = read_at_fixed(ring, m.dir_fd(), m.file_name(), 0).await? {
+        let mut addr_collection = addr_collection.borrow_mut();
+        addr_collection.count_io(block.len() as u64, 0);
+        match reader.feed(&block, matcher, &mut addr_collection) {
+            HeaderParseResult::Done => return Ok(()),
+            HeaderParseResult::NeedMore => {}
+        }
+    }
 
-    let mut pos = 0;
+    let mut buf = Vec::new();
     loop {
-        let ret = read_to_vec(&mut file, buf, read_block_size).await?;
+        buf.clear();
+        let offset = reader.offset();
+        let size = reader.next_read_size();
+        let ret = read_at(ring, m.dir_fd(), m.file_name(), buf, offset, size).await?;
         let num_read = ret.0;
         buf = ret.1;
-        //eprintln!("File pos: {}", file.offset());
-        if num_read == 0 {
-            break;
-        }
         let mut addr_collection = addr_collection.borrow_mut();
-        match process_mail_header(&buf, &mut pos, matcher, &mut *addr_collection) {
+        addr_collection.count_io(num_read as u64, 0);
+        match reader.feed(&buf, matcher, &mut addr_collection) {
             HeaderParseResult::Done => break,
             HeaderParseResult::NeedMore => {}
         }
     }
 
-    close(file).await?;
-
     Ok(())
 }
 
-async fn process(path: PathBuf, matcher: &impl Matcher, addrs: &RefCell<AddrCollection>) {
-    if let Err(e) = process_mail(&path, matcher, addrs).await {
+async fn process(
+    ring: &Handle,
+    mail: Mail,
+    matcher: &impl Matcher,
+    addrs: &RefCell<AddrCollection>,
+) {
+    if let Err(e) = process_mail(ring, &mail, matcher, addrs).await {
         eprintln!("Error: {}", e);
     }
+    addrs.borrow_mut().count_mails(1);
 }
 
-fn process_mails(executor: Executor, matcher: impl Matcher, mails: &Mails) -> AddrCollection {
-    let addrs = RefCell::new(AddrCollection::new());
-    let mut executor = executor;
+async fn process_two(
+    ring: &Handle,
+    a: Mail,
+    b: Option<Mail>,
+    matcher: &impl Matcher,
+    addrs: &RefCell<AddrCollection>,
+) {
+    match b {
+        Some(b) => {
+            join(
+                process(ring, a, matcher, addrs),
+                process(ring, b, matcher, addrs),
+            )
+            .await;
+        }
+        None => process(ring, a, matcher, addrs).await,
+    }
+}
 
-    if let Some(m) = mails.get() {
-        executor.spawn(process(m, &matcher, &addrs));
+fn process_mails(
+    executor: Executor,
+    matcher: impl Matcher,
+    mails: &Mails,
+    addrs: AddrCollection,
+    mut progress: Option<Progress>,
+) -> AddrCollection {
+    let addrs = RefCell::new(addrs);
+    let ring = executor.handle();
+    let mut executor = executor;
+    let mut mails = mails.queue();
+    let mut next_task = || {
+        let a = mails.next()?;
+        Some(process_two(&ring, a, mails.next(), &matcher, &addrs))
+    };
+
+    if let Some(task) = next_task() {
+        executor.spawn(task);
     }
 
     while executor.has_tasks() {
         match executor.poll(false) {
             ExecutorPollResult::Finished => {
-                if let Some(m) = mails.get() {
-                    executor.spawn(process(m, &matcher, &addrs));
+                if let Some(task) = next_task() {
+                    executor.spawn(task);
                 }
             }
             ExecutorPollResult::WouldBlock => {
-                if executor.num_tasks() < executor.max_tasks() {
-                    if let Some(m) = mails.get() {
-                        executor.spawn(process(m, &matcher, &addrs));
+                if executor.num_tasks() * MAILS_PER_TASK < executor.max_chains() {
+                    if let Some(task) = next_task() {
+                        executor.spawn(task);
                     }
                 }
                 if let ExecutorPollResult::Finished = executor.poll(true) {
-                    if let Some(m) = mails.get() {
-                        executor.spawn(process(m, &matcher, &addrs));
+                    if let Some(task) = next_task() {
+                        executor.spawn(task);
                     }
                 }
             }
             ExecutorPollResult::Polled => {}
         }
+        if let Some(progress) = &mut progress {
+            progress.update(&mut addrs.borrow_mut());
+        }
     }
+    addrs.borrow_mut().count_io(0, executor.syscalls());
     std::mem::drop(executor);
     addrs.into_inner()
 }
 
-const QUEUE_SIZE: u32 = 1 << 6;
+fn with_buffer_pool(mut executor: Executor) -> Executor {
+    // One buffer per chain. Without registered buffers, all reads go to the heap instead.
+    let num_buffers = executor.max_chains().min(u16::MAX as usize) as u16;
+    let _ = executor.register_buffer_pool(num_buffers, FIRST_BLOCK_SIZE);
+    executor
+}
+
+impl IoUringBackend<'_> {
+    pub fn capabilities(&self) -> Capabilities {
+        self.main_executor.capabilities()
+    }
+
+    /// Whether files are opened, read and closed in a single linked chain.
+    pub fn fixed_files(&self) -> bool {
+        self.main_executor.fixed_files()
+    }
+
+    pub fn registered_buffers(&self) -> bool {
+        self.main_executor.registered_buffers()
+    }
+}
 
 impl Backend for IoUringBackend<'_> {
-    fn construct() -> Result<Self, crate::BackendError> {
-        let executor = Executor::new(QUEUE_SIZE);
-        let executor = executor
-            .fully_supported()
-            .map_err(|_| crate::BackendError::NotSupported)?;
+    fn construct(options: &ScanOptions) -> Result<Self, crate::BackendError> {
+        let mut builder = Executor::builder().queue_size(options.queue_depth);
+        if let Some(sqpoll) = options.sqpoll {
+            builder = builder.sqpoll(sqpoll.idle_ms);
+            if let Some(cpu) = sqpoll.cpu {
+                builder = builder.sqpoll_cpu(cpu);
+            }
+        }
+        if let Some(timeout) = options.timeout {
+            builder = builder.timeout(timeout);
+        }
+        let executor = builder
+            .build()
+            .and_then(Executor::fully_supported)
+            .map_err(|e| crate::BackendError::NotSupported(e.to_string()))?;
+        if options.timeout.is_some() && !executor.capabilities().link_timeout {
+            eprintln!("Timeouts are not supported by your kernel, ignoring --timeout.");
+        }
         Ok(IoUringBackend {
-            main_executor: executor,
+            executor_builder: builder.fixed_files(executor.fixed_files()),
+            main_executor: with_buffer_pool(executor),
+            threads: options.threads,
+            inode_order: options.inode_order,
+            collect: options.collect.clone(),
         })
     }
-    fn run(self, dir: PathBuf, matcher: impl Matcher) {
-        let mails = &*Box::leak(Box::new(Mails::new(dir)));
-        let num_threads = num_cpus::get();
-        //let num_threads = 1;
-
-        let threads = (1..num_threads)
-            .into_iter()
-            .map(|_| {
-                let m = matcher.clone();
-                std::thread::spawn(move || {
-                    let executor = Executor::new(QUEUE_SIZE);
-                    process_mails(executor, m, mails)
-                })
-            })
-            .collect::<Vec<_>>();
-
-        let mut addrs = process_mails(self.main_executor, matcher, mails);
-        for thread in threads {
-            addrs.merge(thread.join().unwrap());
-        }
-        addrs.print();
+    fn run(
+        self,
+        dir: PathBuf,
+        matcher: impl Matcher,
+        progress: Option<Progress>,
+    ) -> AddrCollection {
+        // Executors can not be sent to other threads, so every thread (including this one)
+        // builds its own with the same settings.
+        std::mem::drop(self.main_executor);
+        let builder = self.executor_builder;
+        scan_threads(
+            dir,
+            self.inode_order,
+            self.threads,
+            &self.collect,
+            matcher,
+            progress,
+            move |matcher, mails, addrs, progress| {
+                let executor = builder.build().expect("Failed to create io_uring");
+                process_mails(with_buffer_pool(executor), matcher, mails, addrs, progress)
+            },
+        )
     }
 }
@@ -1,15 +1,38 @@
-use std::path::PathBuf;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Instant;
 use structopt::StructOpt;
 
+mod addressbook;
+mod addrparse;
+mod batch;
+mod bench;
 mod common;
 mod generic_backend;
+mod interner;
 mod io_uring_backend;
+mod mmap_backend;
+mod output;
+mod picker;
+mod store;
+mod template;
+mod topk;
 
+use common::{AddrCollection, Collect, Progress, SortOrder};
 use generic_backend::GenericBackend;
 use io_uring_backend::IoUringBackend;
+use mmap_backend::MmapBackend;
+use output::{Format, OutputOptions};
+use std::sync::Arc;
+use template::Template;
 
 #[derive(StructOpt)]
-#[structopt(author, about)]
+#[structopt(
+    author,
+    about,
+    setting = structopt::clap::AppSettings::SubcommandsNegateReqs
+)]
 struct Options {
     #[structopt(
         short = "s",
@@ -26,15 +49,236 @@ struct Options {
         help = "Apply fuzzy matching (instead of absolute)"
     )]
     fuzzy: bool,
-    #[structopt(long = "generic-backend", help = "Force generic backend")]
+    #[structopt(
+        short = "I",
+        long = "interactive",
+        help = "Select addresses in an interactive picker and print them as 'Name <addr>'",
+        conflicts_with_all = &["save", "vdir"]
+    )]
+    interactive: bool,
+    #[structopt(
+        short = "b",
+        long = "batch",
+        help = "Read one search query per line from a file ('-' for stdin) and print the results grouped by query, using a single scan. A query may start with its own options (-i, -f, -if or --)",
+        value_name = "file",
+        conflicts_with_all = &["interactive", "save", "vdir"],
+        parse(from_os_str)
+    )]
+    batch: Option<PathBuf>,
+    #[structopt(
+        long = "backend",
+        help = "How mails are read. 'auto' picks a backend depending on whether the mails are in the page cache",
+        default_value = "auto",
+        possible_values = BackendKind::VARIANTS
+    )]
+    backend: BackendKind,
+    #[structopt(
+        long = "generic-backend",
+        help = "Force generic backend (same as --backend generic)"
+    )]
     generic_backend: bool,
-    #[structopt(help = "base directory for recursive mail search", parse(from_os_str))]
-    dir: PathBuf,
+    #[structopt(
+        long = "check-backend",
+        help = "Print which IO-uring operations and features your system supports and which backend would be used, then exit"
+    )]
+    check_backend: bool,
+    #[structopt(
+        short = "j",
+        long = "threads",
+        help = "Number of threads that process mails [default: number of CPUs]"
+    )]
+    threads: Option<usize>,
+    #[structopt(
+        long = "queue-depth",
+        help = "Number of concurrent reads per thread (IO-uring backend)",
+        default_value = "64",
+        parse(try_from_str = parse_queue_depth)
+    )]
+    queue_depth: u32,
+    #[structopt(
+        long = "sqpoll",
+        help = "Let a kernel thread poll for submissions (IO-uring backend), which saves syscalls when scanning cold-cache archives"
+    )]
+    sqpoll: bool,
+    #[structopt(
+        long = "sqpoll-idle",
+        help = "Milliseconds without submissions after which the polling thread goes to sleep [default: 1000]",
+        requires = "sqpoll"
+    )]
+    sqpoll_idle: Option<u32>,
+    #[structopt(
+        long = "sqpoll-cpu",
+        help = "CPU to pin the polling thread to",
+        requires = "sqpoll"
+    )]
+    sqpoll_cpu: Option<u32>,
+    #[structopt(
+        long = "inode-order",
+        help = "Read the mails of each directory in the order of their inode numbers, which is much faster on spinning disks (the mails of a directory are only processed once all of them have been found)"
+    )]
+    inode_order: bool,
+    #[structopt(
+        long = "timeout",
+        help = "Skip mails whose file takes longer than this many milliseconds to open or read (IO-uring backend), e.g. on a stuck network file system",
+        value_name = "ms"
+    )]
+    timeout: Option<u64>,
+    #[structopt(
+        short = "l",
+        long = "load",
+        help = "Load and merge a collection file (may be given multiple times)",
+        value_name = "file",
+        number_of_values = 1,
+        parse(from_os_str)
+    )]
+    load: Vec<PathBuf>,
+    #[structopt(
+        long = "save",
+        help = "Write the collected addresses to a collection file instead of printing them",
+        value_name = "file",
+        parse(from_os_str)
+    )]
+    save: Option<PathBuf>,
+    #[structopt(
+        long = "format",
+        help = "Output format",
+        default_value = "plain",
+        possible_values = Format::VARIANTS
+    )]
+    format: Format,
+    #[structopt(
+        short = "0",
+        long = "null",
+        help = "Terminate each field with a NUL character (plain and tsv formats only)"
+    )]
+    null_separated: bool,
+    #[structopt(
+        long = "vdir",
+        help = "Write one .vcf file per address into a vdir (for vdirsyncer/khard) instead of printing (requires --format vcard3/vcard4)",
+        value_name = "dir",
+        parse(from_os_str)
+    )]
+    vdir: Option<PathBuf>,
+    #[structopt(
+        short = "t",
+        long = "template",
+        help = "Print each address using a template, e.g. '{name|quote} <{addr}>'. Fields: addr, name, names, mailbox, count, first_seen, last_seen, folder, score. Filters: quote, date, unix, json, lower, upper",
+        parse(try_from_str = Template::parse)
+    )]
+    template: Option<Template>,
+    #[structopt(
+        long = "sort",
+        help = "Order of the results [default: count, or score for the queries of --batch]",
+        possible_values = SortOrder::VARIANTS
+    )]
+    sort: Option<SortOrder>,
+    #[structopt(short = "n", long = "limit", help = "Print at most this many results")]
+    limit: Option<usize>,
+    #[structopt(
+        long = "top",
+        help = "Only keep the k most frequent addresses. The mails are scanned twice: once to estimate in bounded memory which addresses are the most frequent ones and once to count these exactly. For huge archives with lots of addresses that were only seen once",
+        value_name = "k",
+        conflicts_with_all = &["interactive", "batch"]
+    )]
+    top: Option<usize>,
+    #[structopt(
+        long = "top-memory",
+        help = "Memory for estimating address frequencies with --top, in MiB [default: 64]",
+        value_name = "MiB",
+        requires = "top"
+    )]
+    top_memory: Option<usize>,
+    #[structopt(
+        long = "min-count",
+        help = "Only print addresses that were seen at least this many times",
+        default_value = "1"
+    )]
+    min_count: u64,
+    #[structopt(
+        help = "base directory for recursive mail search",
+        required_unless_one = &["load", "check-backend"],
+        parse(from_os_str)
+    )]
+    dir: Option<PathBuf>,
+    #[structopt(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(StructOpt)]
+enum Command {
+    #[structopt(
+        name = "bench",
+        about = "Scan a maildir with every available backend and report how fast they are"
+    )]
+    Bench(bench::BenchOptions),
+}
+
+fn parse_queue_depth(s: &str) -> Result<u32, String> {
+    match s.parse() {
+        Ok(depth) if (1..=MAX_QUEUE_DEPTH).contains(&depth) => Ok(depth),
+        _ => Err(format!(
+            "Queue depth must be a number between 1 and {}",
+            MAX_QUEUE_DEPTH
+        )),
+    }
+}
+
+impl Options {
+    fn scan_options(&self) -> ScanOptions {
+        ScanOptions {
+            backend: if self.generic_backend {
+                BackendKind::Generic
+            } else {
+                self.backend
+            },
+            threads: self.threads.unwrap_or_else(num_cpus::get).max(1),
+            queue_depth: self.queue_depth,
+            sqpoll: if self.sqpoll {
+                Some(SqPoll {
+                    idle_ms: self.sqpoll_idle.unwrap_or(1000),
+                    cpu: self.sqpoll_cpu,
+                })
+            } else {
+                None
+            },
+            timeout: self.timeout.map(std::time::Duration::from_millis),
+            inode_order: self.inode_order,
+            collect: Collect::All,
+        }
+    }
+
+    fn output_options(&self) -> OutputOptions {
+        OutputOptions {
+            format: self.format,
+            null_separated: self.null_separated,
+            vdir: self.vdir.clone(),
+            template: self.template.clone(),
+            sort: self.sort.unwrap_or(SortOrder::Count),
+            limit: self.limit,
+            min_count: self.min_count,
+        }
+    }
 }
 
 pub trait Matcher: Clone + Send + 'static {
     fn new(pattern: String) -> Self;
-    fn matches(&self, s: &str) -> bool;
+    fn matches(&self, s: &str) -> bool {
+        self.score(s).is_some()
+    }
+    /// How well `s` matches the pattern (higher is better), or `None` if it does not match at all.
+    fn score(&self, s: &str) -> Option<i64>;
+    /// A string that every matching string contains. Headers that do not contain it are skipped
+    /// without parsing them (see `common::may_contain`).
+    fn literal(&self) -> Option<Literal<'_>> {
+        None
+    }
+}
+
+/// See `Matcher::literal`.
+pub struct Literal<'a> {
+    pub text: &'a str,
+    /// `text` is lowercase and strings containing it in any case match.
+    pub ignore_case: bool,
 }
 
 #[derive(Clone)]
@@ -46,6 +290,15 @@ impl<M: Matcher> Matcher for CaseInsensitiveMatcher<M> {
     fn matches(&self, s: &str) -> bool {
         self.0.matches(&s.to_lowercase())
     }
+    fn score(&self, s: &str) -> Option<i64> {
+        self.0.score(&s.to_lowercase())
+    }
+    fn literal(&self) -> Option<Literal<'_>> {
+        self.0.literal().map(|literal| Literal {
+            text: literal.text,
+            ignore_case: true,
+        })
+    }
 }
 
 #[derive(Clone)]
@@ -57,6 +310,24 @@ impl Matcher for SubstringMatcher {
     fn matches(&self, s: &str) -> bool {
         s.contains(&self.0)
     }
+    fn score(&self, s: &str) -> Option<i64> {
+        // Prefer exact matches over prefix matches over matches anywhere else.
+        if s == self.0 {
+            Some(3)
+        } else if s.starts_with(&self.0) {
+            Some(2)
+        } else if s.contains(&self.0) {
+            Some(1)
+        } else {
+            None
+        }
+    }
+    fn literal(&self) -> Option<Literal<'_>> {
+        Some(Literal {
+            text: &self.0,
+            ignore_case: false,
+        })
+    }
 }
 
 #[derive(Clone)]
@@ -70,50 +341,453 @@ impl Matcher for FuzzyMatcher {
             .fuzzy(s, &self.0, false)
             .is_some()
     }
+    fn score(&self, s: &str) -> Option<i64> {
+        fuzzy_matcher::skim::SkimMatcherV2::default()
+            .fuzzy(s, &self.0, false)
+            .map(|(score, _)| score)
+    }
+}
+
+const MAX_QUEUE_DEPTH: u32 = 4096;
+
+/// Kernel side submission queue polling (see `IORING_SETUP_SQPOLL`).
+#[derive(Copy, Clone, Debug)]
+pub struct SqPoll {
+    pub idle_ms: u32,
+    pub cpu: Option<u32>,
+}
+
+/// How mails are read (see `Backend`).
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum BackendKind {
+    /// Pick one of the others, see `auto_backend`.
+    Auto,
+    Generic,
+    IoUring,
+    Mmap,
+}
+
+impl BackendKind {
+    pub const VARIANTS: &'static [&'static str] = &["auto", "generic", "io-uring", "mmap"];
+
+    /// All backends except `Auto`.
+    pub const ALL: &'static [BackendKind] = &[
+        BackendKind::Generic,
+        BackendKind::IoUring,
+        BackendKind::Mmap,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            BackendKind::Auto => "auto",
+            BackendKind::Generic => "generic",
+            BackendKind::IoUring => "io-uring",
+            BackendKind::Mmap => "mmap",
+        }
+    }
+}
+
+impl FromStr for BackendKind {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "auto" => Ok(BackendKind::Auto),
+            "generic" => Ok(BackendKind::Generic),
+            "io-uring" => Ok(BackendKind::IoUring),
+            "mmap" => Ok(BackendKind::Mmap),
+            _ => Err(format!("Unknown backend: {}", s)),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct ScanOptions {
+    pub backend: BackendKind,
+    pub threads: usize,
+    /// Only used by the IO-uring backend.
+    pub queue_depth: u32,
+    /// Only used by the IO-uring backend.
+    pub sqpoll: Option<SqPoll>,
+    /// Per open/read of a mail. Only used by the IO-uring backend.
+    pub timeout: Option<std::time::Duration>,
+    /// Hand out the mails of each directory sorted by inode number.
+    pub inode_order: bool,
+    pub collect: Collect,
 }
 
 #[derive(Debug)]
 enum BackendError {
-    NotSupported,
+    NotSupported(String),
 }
 trait Backend: Sized {
-    fn construct() -> Result<Self, BackendError>;
-    fn run(self, dir: PathBuf, matcher: impl Matcher);
+    fn construct(options: &ScanOptions) -> Result<Self, BackendError>;
+    fn run(self, dir: PathBuf, matcher: impl Matcher, progress: Option<Progress>)
+        -> AddrCollection;
 }
 
-fn run_backend(backend: impl Backend, options: Options) {
-    // Somewhat ugly, but what we need for static dispatch
-    if options.fuzzy {
-        if options.ignore_case {
-            backend.run(
-                options.dir,
-                CaseInsensitiveMatcher::<FuzzyMatcher>::new(options.search_string),
-            )
-        } else {
-            backend.run(options.dir, FuzzyMatcher::new(options.search_string))
+/// Number of files whose page cache residency is checked to pick a backend.
+const RESIDENCY_SAMPLES: usize = 64;
+
+/// Pick a backend for scanning `dir`. Mails that still have to be read from disk profit from the
+/// many concurrent reads of the IO-uring backend, so it is used unless most of a sample of the
+/// mails is in the page cache already.
+///
+/// With a timeout, the IO-uring backend is picked without looking at the mails: it is the only one
+/// that supports timeouts, and reading the sample might hang on the very file system that the
+/// timeout is meant for.
+fn auto_backend(dir: &Path, options: &ScanOptions) -> BackendKind {
+    if options.timeout.is_some() {
+        return BackendKind::IoUring;
+    }
+    match common::page_cache_residency(dir, RESIDENCY_SAMPLES) {
+        Some(residency) if residency >= 0.5 => BackendKind::Generic,
+        _ => BackendKind::IoUring,
+    }
+}
+
+/// Scan `dir` with a specific backend (not `Auto`).
+fn scan_with(
+    backend: BackendKind,
+    dir: PathBuf,
+    matcher: impl Matcher,
+    options: &ScanOptions,
+    progress: Option<Progress>,
+) -> Result<AddrCollection, BackendError> {
+    Ok(match backend {
+        BackendKind::Auto => unreachable!("Auto has to be resolved first"),
+        BackendKind::Generic => GenericBackend::construct(options)?.run(dir, matcher, progress),
+        BackendKind::IoUring => IoUringBackend::construct(options)?.run(dir, matcher, progress),
+        BackendKind::Mmap => MmapBackend::construct(options)?.run(dir, matcher, progress),
+    })
+}
+
+/// Scan `dir` with the backend of the `options`. Fails if that backend was picked explicitly and
+/// is not supported, otherwise unsupported backends fall back to the generic one.
+fn scan(
+    dir: PathBuf,
+    matcher: impl Matcher,
+    options: &ScanOptions,
+    progress: Option<Progress>,
+) -> Result<AddrCollection, String> {
+    let backend = match options.backend {
+        BackendKind::Auto => auto_backend(&dir, options),
+        backend => backend,
+    };
+    let err = match scan_with(
+        backend,
+        dir.clone(),
+        matcher.clone(),
+        options,
+        progress.clone(),
+    ) {
+        Ok(addrs) => return Ok(addrs),
+        Err(BackendError::NotSupported(reason)) => reason,
+    };
+    if options.backend != BackendKind::Auto {
+        return Err(format!(
+            "The {} backend is not supported on your system ({}).",
+            backend.name(),
+            err
+        ));
+    }
+    if options.sqpoll.is_some() {
+        eprintln!("IO-uring backend with SQPOLL is not supported on your system ({}). (Linux Kernel version 5.11 or above is required.) Falling back to generic backend.", err);
+    } else {
+        eprintln!(
+            "IO-uring backend is not supported on your system ({}). Falling back to generic backend.",
+            err
+        );
+    }
+    if options.timeout.is_some() {
+        eprintln!("The generic backend does not support timeouts, ignoring --timeout.");
+    }
+    Ok(GenericBackend::construct(options)
+        .unwrap()
+        .run(dir, matcher, progress))
+}
+
+/// Scan `dir` for the `k` most frequent addresses (see `topk`), with `memory` bytes for all
+/// sketches together.
+fn scan_top(
+    dir: PathBuf,
+    matcher: impl Matcher,
+    options: &ScanOptions,
+    k: usize,
+    memory: usize,
+) -> Result<AddrCollection, String> {
+    // Every thread has its own sketch.
+    let top = topk::TopK {
+        k,
+        sketch_bytes: memory / options.threads,
+    };
+    let estimate_options = ScanOptions {
+        collect: Collect::Candidates(top),
+        ..options.clone()
+    };
+    let candidates = scan(dir.clone(), matcher.clone(), &estimate_options, None)?.into_candidates();
+    let count_options = ScanOptions {
+        collect: Collect::Only(Arc::new(candidates)),
+        ..options.clone()
+    };
+    scan(dir, matcher, &count_options, None)
+}
+
+fn kernel_release() -> Option<String> {
+    // Safety: utsname is plain old data, so zeroed memory is a valid value.
+    let mut uts: libc::utsname = unsafe { std::mem::zeroed() };
+    // Safety: uts is a valid utsname.
+    if unsafe { libc::uname(&mut uts) } != 0 {
+        return None;
+    }
+    // Safety: uname fills release with a nul-terminated string.
+    let release = unsafe { std::ffi::CStr::from_ptr(uts.release.as_ptr()) };
+    Some(release.to_string_lossy().into_owned())
+}
+
+/// Print what the IO-uring backend can use on this system and which backend `scan` would use.
+fn check_backend(options: &ScanOptions) {
+    let yes_no = |b: bool| if b { "yes" } else { "no" };
+    println!(
+        "Kernel: {}",
+        kernel_release().unwrap_or_else(|| "unknown".to_owned())
+    );
+    let backend = match IoUringBackend::construct(options) {
+        Ok(backend) => {
+            println!("IO-uring: available");
+            for (name, supported) in backend.capabilities().list().iter() {
+                println!("  {:<20}{}", name, yes_no(*supported));
+            }
+            println!("  {:<20}{}", "fixed files", yes_no(backend.fixed_files()));
+            println!(
+                "  {:<20}{}",
+                "registered buffers",
+                yes_no(backend.registered_buffers())
+            );
+            if options.sqpoll.is_some() {
+                println!("  {:<20}yes", "sqpoll");
+            }
+            "io-uring (generic for mails that are in the page cache)"
+        }
+        Err(BackendError::NotSupported(reason)) => {
+            println!("IO-uring: not supported ({})", reason);
+            "generic"
         }
+    };
+    if options.backend != BackendKind::Auto {
+        println!("Backend: {} (forced)", options.backend.name());
     } else {
-        if options.ignore_case {
-            backend.run(
-                options.dir,
-                CaseInsensitiveMatcher::<SubstringMatcher>::new(options.search_string),
+        println!("Backend: {}", backend);
+    }
+}
+
+fn load(paths: &[PathBuf], matcher: &impl Matcher) -> AddrCollection {
+    let mut addrs = AddrCollection::new();
+    for path in paths {
+        match store::load(path) {
+            Ok(mut loaded) => {
+                loaded.retain_matching(matcher);
+                addrs.merge(loaded);
+            }
+            Err(e) => {
+                eprintln!("Failed to load {}: {}", path.display(), e);
+                std::process::exit(1);
+            }
+        }
+    }
+    addrs
+}
+
+/// The result of a scan, or exit if it failed.
+fn scanned_or_exit(result: Result<AddrCollection, String>) -> AddrCollection {
+    result.unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    })
+}
+
+fn run_interactive<M: Matcher>(options: Options) {
+    // Everything is collected, filtering happens in the picker while typing.
+    let match_all = M::new(String::new());
+    let addrs = load(&options.load, &match_all);
+
+    let (sender, updates) = crossbeam_channel::unbounded();
+    if let Some(dir) = options.dir.clone() {
+        let scan_options = options.scan_options();
+        std::thread::spawn(move || {
+            let progress = Progress::new(sender.clone());
+            // The picker restores the terminal before it reports an error.
+            let rest = scan(dir, match_all, &scan_options, Some(progress));
+            let _ = sender.send(rest);
+        });
+    }
+
+    let output_options = options.output_options();
+    match picker::pick::<M>(addrs, updates, options.search_string, &output_options) {
+        Ok(Some(selection)) => {
+            let terminator = if output_options.null_separated {
+                '\0'
+            } else {
+                '\n'
+            };
+            for line in selection {
+                print!("{}{}", line, terminator);
+            }
+        }
+        Ok(None) => std::process::exit(1),
+        Err(e) => {
+            eprintln!("Interactive mode failed: {}", e);
+            std::process::exit(2);
+        }
+    }
+}
+
+fn run_batch(options: Options, path: &Path) {
+    let queries = match batch::read_queries(path, options.ignore_case, options.fuzzy) {
+        Ok(queries) => queries,
+        Err(e) => {
+            eprintln!("Failed to read queries from {}: {}", path.display(), e);
+            std::process::exit(1);
+        }
+    };
+    let matcher = batch::AnyMatcher::for_queries(&queries);
+    let mut addrs = match &options.dir {
+        Some(dir) => scanned_or_exit(scan(
+            dir.clone(),
+            matcher.clone(),
+            &options.scan_options(),
+            None,
+        )),
+        None => AddrCollection::new(),
+    };
+    addrs.merge(load(&options.load, &matcher));
+
+    let output_options = options.output_options();
+    // Like in the picker, the best matches come first unless another order was requested.
+    let score_options = OutputOptions {
+        sort: SortOrder::Score,
+        ..output_options.clone()
+    };
+    let results = queries
+        .iter()
+        .map(|query| {
+            let query_options = if options.sort.is_none() && !query.search.is_empty() {
+                &score_options
+            } else {
+                &output_options
+            };
+            (
+                query.search.as_str(),
+                addrs.entries(&query.matcher, query_options),
             )
-        } else {
-            backend.run(options.dir, SubstringMatcher::new(options.search_string))
+        })
+        .collect::<Vec<_>>();
+    let stdout = std::io::stdout();
+    let mut stdout = std::io::BufWriter::new(stdout.lock());
+    let _ =
+        output::write_batch(&mut stdout, &results, &output_options).and_then(|_| stdout.flush());
+}
+
+fn run<M: Matcher>(options: Options, matcher: M) {
+    if options.interactive {
+        return run_interactive::<M>(options);
+    }
+
+    let start = Instant::now();
+    let mut addrs = match (&options.dir, options.top) {
+        (Some(dir), Some(k)) => scanned_or_exit(scan_top(
+            dir.clone(),
+            matcher.clone(),
+            &options.scan_options(),
+            k,
+            options.top_memory.unwrap_or(64) << 20,
+        )),
+        (Some(dir), None) => scanned_or_exit(scan(
+            dir.clone(),
+            matcher.clone(),
+            &options.scan_options(),
+            None,
+        )),
+        (None, _) => AddrCollection::new(),
+    };
+    addrs.merge(load(&options.load, &matcher));
+    if let Some(k) = options.top {
+        addrs.retain_top(k);
+    }
+
+    if let Some(path) = &options.save {
+        if let Err(e) = store::save(&addrs, path) {
+            eprintln!("Failed to save {}: {}", path.display(), e);
+            std::process::exit(1);
+        }
+    } else if let Some(dir) = &options.vdir {
+        let output_options = options.output_options();
+        let version = output_options.vcard_version().unwrap();
+        let entries = addrs.entries(&matcher, &output_options);
+        if let Err(e) = addressbook::write_vdir(dir, entries, version) {
+            eprintln!("Failed to write vdir {}: {}", dir.display(), e);
+            std::process::exit(1);
         }
+    } else {
+        addrs.print(&matcher, &options.output_options(), start.elapsed());
     }
 }
 
 fn main() {
     let options = Options::from_args();
-    if options.generic_backend {
-        run_backend(GenericBackend::construct().unwrap(), options);
-    } else {
-        if let Ok(backend) = IoUringBackend::construct() {
-            run_backend(backend, options);
+    if options.check_backend {
+        return check_backend(&options.scan_options());
+    }
+    if let Some(Command::Bench(bench_options)) = &options.command {
+        return bench::run(bench_options, &options.scan_options());
+    }
+    let backend = options.scan_options().backend;
+    if options.timeout.is_some() && backend != BackendKind::Auto && backend != BackendKind::IoUring
+    {
+        structopt::clap::Error::with_description(
+            &format!(
+                "--timeout is not supported by the {} backend (only by io-uring)",
+                backend.name()
+            ),
+            structopt::clap::ErrorKind::ArgumentConflict,
+        )
+        .exit();
+    }
+    if let Err(e) = options.output_options().validate() {
+        structopt::clap::Error::with_description(&e, structopt::clap::ErrorKind::ArgumentConflict)
+            .exit();
+    }
+    if let Some(path) = options.batch.clone() {
+        let error = if !options.search_string.is_empty() {
+            Some("--search cannot be combined with --batch".to_owned())
+        } else {
+            options.output_options().validate_batch().err()
+        };
+        if let Some(e) = error {
+            structopt::clap::Error::with_description(
+                &e,
+                structopt::clap::ErrorKind::ArgumentConflict,
+            )
+            .exit();
+        }
+        return run_batch(options, &path);
+    }
+    // Somewhat ugly, but what we need for static dispatch
+    let search_string = options.search_string.clone();
+    if options.fuzzy {
+        if options.ignore_case {
+            run(
+                options,
+                CaseInsensitiveMatcher::<FuzzyMatcher>::new(search_string),
+            )
         } else {
-            eprintln!("IO-uring backend is not (fully) on your system supported. (Linux Kernel version 5.6 or above is required.) Falling back to generic backend.");
-            run_backend(GenericBackend::construct().unwrap(), options);
+            run(options, FuzzyMatcher::new(search_string))
         }
+    } else if options.ignore_case {
+        run(
+            options,
+            CaseInsensitiveMatcher::<SubstringMatcher>::new(search_string),
+        )
+    } else {
+        run(options, SubstringMatcher::new(search_string))
     }
 }
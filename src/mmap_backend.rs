@@ -0,0 +1,167 @@
+use crate::common::{
+    scan_mails, scan_threads, AddrCollection, Collect, HeaderParseResult, HeaderReader, Mail,
+    Progress,
+};
+use crate::{Backend, Matcher, ScanOptions};
+use std::fs::File;
+use std::io::{Error, Read, Result};
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::path::PathBuf;
+
+/// A read only, private mapping of a whole file.
+struct Mapping {
+    ptr: *mut libc::c_void,
+    len: usize,
+}
+
+impl Mapping {
+    /// Map the first `len` bytes of `file`, which must not be empty. Pages are only read (and
+    /// read ahead) when the header parser touches them.
+    fn new(file: &File, len: usize) -> Result<Self> {
+        // Safety: A fresh mapping does not alias any memory we own. No MAP_POPULATE, since most
+        // of a mail is usually behind its header.
+        let ptr = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                len,
+                libc::PROT_READ,
+                libc::MAP_PRIVATE,
+                file.as_raw_fd(),
+                0,
+            )
+        };
+        if ptr == libc::MAP_FAILED {
+            return Err(Error::last_os_error());
+        }
+        let mapping = Mapping { ptr, len };
+        // Safety: The range is exactly the mapping. The advice is only a hint, so errors are
+        // ignored.
+        unsafe { libc::madvise(mapping.ptr, mapping.len, libc::MADV_SEQUENTIAL) };
+        Ok(mapping)
+    }
+
+    fn bytes(&self) -> &[u8] {
+        // Safety: The mapping is readable and alive as long as self. Only delivered mails are
+        // mapped (see `process_mail`), which are never modified in place, so the contents do not
+        // change while they are mapped. If another program truncates the file anyway, touching
+        // the pages behind the new end raises SIGBUS, which kills maq.
+        unsafe { std::slice::from_raw_parts(self.ptr as *const u8, self.len) }
+    }
+}
+
+impl Drop for Mapping {
+    fn drop(&mut self) {
+        // Safety: ptr and len describe a mapping that is not referenced anymore.
+        unsafe { libc::munmap(self.ptr, self.len) };
+    }
+}
+
+fn open(m: &Mail) -> Result<File> {
+    // Safety: dir_fd is valid as long as m is alive and file_name is nul terminated.
+    let fd = unsafe {
+        libc::openat(
+            m.dir_fd(),
+            m.file_name().as_ptr(),
+            // Does not block if a symlink leads to a special file, which is skipped then.
+            libc::O_RDONLY | libc::O_CLOEXEC | libc::O_NONBLOCK,
+        )
+    };
+    if fd < 0 {
+        return Err(Error::last_os_error());
+    }
+    // Safety: fd is a freshly opened descriptor that nobody else owns.
+    Ok(unsafe { File::from_raw_fd(fd) })
+}
+
+/// Read the header of a file that may change while it is read (see `process_mail`) into a
+/// buffer instead of mapping it.
+fn read_mail(
+    mut file: File,
+    mut reader: HeaderReader,
+    matcher: &impl Matcher,
+    addrs: &mut AddrCollection,
+) -> Result<()> {
+    let mut buf = Vec::new();
+    loop {
+        buf.resize(reader.next_read_size(), 0);
+        let num_read = file.read(&mut buf)?;
+        addrs.count_io(num_read as u64, 1);
+        match reader.feed(&buf[..num_read], matcher, addrs) {
+            HeaderParseResult::Done => break,
+            HeaderParseResult::NeedMore => {}
+        }
+    }
+    Ok(())
+}
+
+fn process_mail(m: Mail, matcher: &impl Matcher, addrs: &mut AddrCollection) -> Result<()> {
+    let mut reader = HeaderReader::new(m.info());
+    let file = open(&m)?;
+    let metadata = file.metadata()?;
+    // open, fstat and close
+    addrs.count_io(0, 3);
+    if !metadata.is_file() {
+        return Ok(());
+    }
+    // Anything but a delivered mail (e.g. an index of a mail client) may be truncated while it is
+    // mapped, see `Mapping::bytes`.
+    if !m.is_delivered() {
+        return read_mail(file, reader, matcher, addrs);
+    }
+    let len = metadata.len() as usize;
+    if len == 0 {
+        return Ok(());
+    }
+    let mapping = Mapping::new(&file, len)?;
+    // The mapping stays valid without the descriptor.
+    std::mem::drop(file);
+    // mmap, madvise and munmap
+    addrs.count_io(0, 3);
+
+    // The whole file is a single chunk, which is parsed in place.
+    let _ = reader.feed(mapping.bytes(), matcher, addrs);
+    // Bytes the header parser has looked at. These (rounded up to whole pages, plus read ahead)
+    // are what is actually read from the file.
+    addrs.count_io(reader.parsed() as u64, 0);
+    Ok(())
+}
+
+/// Maps every mail into memory and parses the header straight from the mapping, without copying
+/// it into a buffer first.
+pub struct MmapBackend {
+    threads: usize,
+    inode_order: bool,
+    collect: Collect,
+}
+
+impl Backend for MmapBackend {
+    fn construct(options: &ScanOptions) -> std::result::Result<Self, crate::BackendError> {
+        Ok(MmapBackend {
+            threads: options.threads,
+            inode_order: options.inode_order,
+            collect: options.collect.clone(),
+        })
+    }
+    fn run(
+        self,
+        dir: PathBuf,
+        matcher: impl Matcher,
+        progress: Option<Progress>,
+    ) -> AddrCollection {
+        scan_threads(
+            dir,
+            self.inode_order,
+            self.threads,
+            &self.collect,
+            matcher,
+            progress,
+            |matcher, mails, addrs, progress| {
+                scan_mails(matcher, mails, addrs, progress, |m, matcher, addrs| {
+                    if let Err(e) = process_mail(m, matcher, addrs) {
+                        eprintln!("Error: {}", e);
+                    }
+                })
+            },
+        )
+    }
+}
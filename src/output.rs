@@ -0,0 +1,470 @@
+use crate::addressbook::{
+    write_abook_entry, write_abook_header, write_vcard, MuttAliasWriter, VCardVersion,
+};
+use crate::common::{AddrData, Entry, SortOrder};
+use crate::template::Template;
+use serde::Serialize;
+use std::io::Write;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Duration;
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Format {
+    Plain,
+    Json,
+    Jsonl,
+    Csv,
+    Tsv,
+    Mutt,
+    VCard(VCardVersion),
+    MuttAliases,
+    Abook,
+}
+
+impl Format {
+    pub const VARIANTS: &'static [&'static str] = &[
+        "plain",
+        "json",
+        "jsonl",
+        "csv",
+        "tsv",
+        "mutt",
+        "vcard3",
+        "vcard4",
+        "mutt-aliases",
+        "abook",
+    ];
+
+    fn supports_null_separation(self) -> bool {
+        matches!(self, Format::Plain | Format::Tsv)
+    }
+}
+
+impl FromStr for Format {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "plain" => Ok(Format::Plain),
+            "json" => Ok(Format::Json),
+            "jsonl" => Ok(Format::Jsonl),
+            "csv" => Ok(Format::Csv),
+            "tsv" => Ok(Format::Tsv),
+            "mutt" => Ok(Format::Mutt),
+            "vcard3" => Ok(Format::VCard(VCardVersion::V3)),
+            "vcard4" => Ok(Format::VCard(VCardVersion::V4)),
+            "mutt-aliases" => Ok(Format::MuttAliases),
+            "abook" => Ok(Format::Abook),
+            _ => Err(format!("Unknown output format: {}", s)),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct OutputOptions {
+    pub format: Format,
+    pub null_separated: bool,
+    pub vdir: Option<PathBuf>,
+    pub template: Option<Template>,
+    pub sort: SortOrder,
+    pub limit: Option<usize>,
+    pub min_count: u64,
+}
+
+impl OutputOptions {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.template.is_some() {
+            if self.format != Format::Plain || self.vdir.is_some() {
+                return Err("--template cannot be combined with --format or --vdir".into());
+            }
+            return Ok(());
+        }
+        if self.null_separated && !self.format.supports_null_separation() {
+            return Err(
+                "NUL-separated output is only supported for the plain and tsv formats".into(),
+            );
+        }
+        if self.vdir.is_some() && self.vcard_version().is_none() {
+            return Err("Writing a vdir requires --format vcard3 or vcard4".into());
+        }
+        Ok(())
+    }
+
+    /// Batch mode prints the results grouped by query, which only some formats can express.
+    pub fn validate_batch(&self) -> Result<(), String> {
+        if self.template.is_some() {
+            return Err("--template cannot be combined with --batch".into());
+        }
+        match self.format {
+            Format::Plain | Format::Json | Format::Jsonl | Format::Csv | Format::Tsv => Ok(()),
+            _ => Err("--batch only supports the plain, json, jsonl, csv and tsv formats".into()),
+        }
+    }
+
+    pub fn vcard_version(&self) -> Option<VCardVersion> {
+        match self.format {
+            Format::VCard(version) => Some(version),
+            _ => None,
+        }
+    }
+}
+
+/// Information about the scan that produced the printed entries.
+pub struct Summary {
+    pub num_mails: u64,
+    pub elapsed: Duration,
+}
+
+/// Split seconds since the epoch into (year, month, day) and seconds of the day (UTC).
+fn civil_from_timestamp(timestamp: i64) -> ((i64, u32, u32), u32) {
+    // See http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+    let days = timestamp.div_euclid(86400);
+    let secs = timestamp.rem_euclid(86400) as u32;
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    ((year, month, day), secs)
+}
+
+pub fn format_date(timestamp: i64) -> String {
+    let ((year, month, day), _) = civil_from_timestamp(timestamp);
+    format!("{:04}-{:02}-{:02}", year, month, day)
+}
+
+pub fn format_datetime(timestamp: i64) -> String {
+    let ((year, month, day), secs) = civil_from_timestamp(timestamp);
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year,
+        month,
+        day,
+        secs / 3600,
+        secs / 60 % 60,
+        secs % 60
+    )
+}
+
+/// Format a display name for use in an RFC 5322 mailbox (`name <addr>`), i.e., quote it unless
+/// it only consists of atoms.
+pub fn quote_display_name(name: &str) -> String {
+    fn is_atext(c: char) -> bool {
+        c.is_ascii_alphanumeric() || "!#$%&'*+-/=?^_`{|}~".contains(c) || !c.is_ascii()
+    }
+    let is_phrase = !name.trim().is_empty()
+        && name.trim() == name
+        && name
+            .split(' ')
+            .all(|atom| !atom.is_empty() && atom.chars().all(is_atext));
+    if is_phrase {
+        name.to_owned()
+    } else {
+        format!("\"{}\"", name.replace('\\', "\\\\").replace('"', "\\\""))
+    }
+}
+
+#[derive(Serialize)]
+struct NameVariant<'a> {
+    name: &'a str,
+    count: u64,
+}
+
+#[derive(Serialize)]
+struct JsonEntry<'a> {
+    addr: &'a str,
+    name: Option<&'a str>,
+    count: u64,
+    names: Vec<NameVariant<'a>>,
+    first_seen: Option<String>,
+    last_seen: Option<String>,
+    folder: Option<&'a str>,
+}
+
+impl<'a> JsonEntry<'a> {
+    fn new(entry: &Entry<'a>) -> Self {
+        let data = entry.data;
+        JsonEntry {
+            addr: entry.addr,
+            name: entry.preferred_name(),
+            count: data.occurences,
+            names: entry
+                .sorted_name_variants()
+                .into_iter()
+                .map(|(name, count)| NameVariant { name, count })
+                .collect(),
+            first_seen: data.first_seen.map(format_datetime),
+            last_seen: data.last_seen.map(format_datetime),
+            folder: data.last_folder.as_deref(),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct JsonQueryResults<'a> {
+    query: &'a str,
+    results: Vec<JsonEntry<'a>>,
+}
+
+impl<'a> JsonQueryResults<'a> {
+    fn new(query: &'a str, entries: &[Entry<'a>]) -> Self {
+        JsonQueryResults {
+            query,
+            results: entries.iter().map(JsonEntry::new).collect(),
+        }
+    }
+}
+
+const COLUMNS: &[&str] = &[
+    "addr",
+    "name",
+    "count",
+    "names",
+    "first_seen",
+    "last_seen",
+    "folder",
+];
+
+fn columns(entry: &Entry) -> [String; 7] {
+    let data = entry.data;
+    let names = entry
+        .sorted_name_variants()
+        .into_iter()
+        .map(|(name, _)| name)
+        .collect::<Vec<_>>();
+    [
+        entry.addr.to_owned(),
+        entry.preferred_name().unwrap_or("").to_owned(),
+        data.occurences.to_string(),
+        names.join("\n"),
+        data.first_seen.map(format_datetime).unwrap_or_default(),
+        data.last_seen.map(format_datetime).unwrap_or_default(),
+        data.last_folder.as_deref().unwrap_or_default().to_owned(),
+    ]
+}
+
+/// Mutt splits query results at tabs and expects one result per line.
+fn sanitize_mutt_field(field: &str) -> String {
+    field.replace(&['\t', '\n', '\r'][..], " ")
+}
+
+fn mutt_info(data: &AddrData) -> String {
+    let mut info = format!("seen {}x", data.occurences);
+    if let Some(last_seen) = data.last_seen {
+        info.push_str(&format!(", last {}", format_date(last_seen)));
+    }
+    match data.last_folder.as_deref() {
+        Some(folder) if !folder.is_empty() => info.push_str(&format!(" in {}", folder)),
+        _ => {}
+    }
+    sanitize_mutt_field(&info)
+}
+
+fn escape_csv(field: &str) -> String {
+    if field.contains(&[',', '"', '\n', '\r'][..]) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_owned()
+    }
+}
+
+fn escape_tsv(field: &str) -> String {
+    let mut escaped = String::with_capacity(field.len());
+    for c in field.chars() {
+        match c {
+            '\\' => escaped.push_str("\\\\"),
+            '\t' => escaped.push_str("\\t"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+fn write_fields<'a>(
+    out: &mut impl Write,
+    fields: impl IntoIterator<Item = &'a str>,
+    options: &OutputOptions,
+) -> std::io::Result<()> {
+    if options.null_separated {
+        for field in fields {
+            write!(out, "{}\0", field)?;
+        }
+    } else {
+        let fields = fields.into_iter().collect::<Vec<_>>();
+        writeln!(out, "{}", fields.join("\t"))?;
+    }
+    Ok(())
+}
+
+pub fn write_entries<'a>(
+    out: &mut impl Write,
+    entries: impl IntoIterator<Item = Entry<'a>>,
+    summary: &Summary,
+    options: &OutputOptions,
+) -> std::io::Result<()> {
+    if let Some(template) = &options.template {
+        let terminator = if options.null_separated { '\0' } else { '\n' };
+        for entry in entries {
+            write!(out, "{}{}", template.render(&entry), terminator)?;
+        }
+        return Ok(());
+    }
+    match options.format {
+        Format::Plain => {
+            // Mutt expects the first line to be a status message.
+            if !options.null_separated {
+                writeln!(out)?;
+            }
+            for entry in entries {
+                let name = entry.preferred_name().unwrap_or("");
+                write_fields(out, [entry.addr, name], options)?;
+            }
+        }
+        Format::Mutt => {
+            let entries = entries.into_iter().collect::<Vec<_>>();
+            writeln!(
+                out,
+                "{} matches in {:.1}s ({} mails)",
+                entries.len(),
+                summary.elapsed.as_secs_f64(),
+                summary.num_mails
+            )?;
+            for entry in entries {
+                writeln!(
+                    out,
+                    "{}\t{}\t{}",
+                    entry.addr,
+                    sanitize_mutt_field(entry.preferred_name().unwrap_or("")),
+                    mutt_info(entry.data)
+                )?;
+            }
+        }
+        Format::Json => {
+            let entries = entries
+                .into_iter()
+                .map(|entry| JsonEntry::new(&entry))
+                .collect::<Vec<_>>();
+            serde_json::to_writer(&mut *out, &entries)?;
+            writeln!(out)?;
+        }
+        Format::Jsonl => {
+            for entry in entries {
+                serde_json::to_writer(&mut *out, &JsonEntry::new(&entry))?;
+                writeln!(out)?;
+            }
+        }
+        Format::Csv => {
+            writeln!(out, "{}", COLUMNS.join(","))?;
+            for entry in entries {
+                let fields = columns(&entry)
+                    .iter()
+                    .map(|f| escape_csv(f))
+                    .collect::<Vec<_>>();
+                writeln!(out, "{}", fields.join(","))?;
+            }
+        }
+        Format::Tsv => {
+            if !options.null_separated {
+                write_fields(out, COLUMNS.iter().copied(), options)?;
+            }
+            for entry in entries {
+                let fields = columns(&entry);
+                if options.null_separated {
+                    write_fields(out, fields.iter().map(|f| f.as_str()), options)?;
+                } else {
+                    let fields = fields.iter().map(|f| escape_tsv(f)).collect::<Vec<_>>();
+                    write_fields(out, fields.iter().map(|f| f.as_str()), options)?;
+                }
+            }
+        }
+        Format::VCard(version) => {
+            for entry in entries {
+                write_vcard(out, &entry, version)?;
+            }
+        }
+        Format::MuttAliases => {
+            let mut writer = MuttAliasWriter::new();
+            for entry in entries {
+                writer.write(out, &entry)?;
+            }
+        }
+        Format::Abook => {
+            write_abook_header(out)?;
+            for (i, entry) in entries.into_iter().enumerate() {
+                write_abook_entry(out, i, &entry)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Write the results of several queries (see `OutputOptions::validate_batch` for the supported
+/// formats). Line based formats get an additional leading column with the query.
+pub fn write_batch<'a>(
+    out: &mut impl Write,
+    results: &[(&'a str, Vec<Entry<'a>>)],
+    options: &OutputOptions,
+) -> std::io::Result<()> {
+    match options.format {
+        Format::Plain => {
+            for (query, entries) in results {
+                for entry in entries {
+                    let name = entry.preferred_name().unwrap_or("");
+                    write_fields(out, [*query, entry.addr, name], options)?;
+                }
+            }
+        }
+        Format::Json => {
+            let results = results
+                .iter()
+                .map(|(query, entries)| JsonQueryResults::new(query, entries))
+                .collect::<Vec<_>>();
+            serde_json::to_writer(&mut *out, &results)?;
+            writeln!(out)?;
+        }
+        Format::Jsonl => {
+            for (query, entries) in results {
+                serde_json::to_writer(&mut *out, &JsonQueryResults::new(query, entries))?;
+                writeln!(out)?;
+            }
+        }
+        Format::Csv => {
+            writeln!(out, "query,{}", COLUMNS.join(","))?;
+            for (query, entries) in results {
+                for entry in entries {
+                    let fields = std::iter::once(escape_csv(query))
+                        .chain(columns(entry).iter().map(|f| escape_csv(f)))
+                        .collect::<Vec<_>>();
+                    writeln!(out, "{}", fields.join(","))?;
+                }
+            }
+        }
+        Format::Tsv => {
+            if !options.null_separated {
+                let header = std::iter::once("query").chain(COLUMNS.iter().copied());
+                write_fields(out, header, options)?;
+            }
+            for (query, entries) in results {
+                for entry in entries {
+                    let fields = std::iter::once(query.to_string())
+                        .chain(columns(entry).iter().cloned())
+                        .collect::<Vec<_>>();
+                    let fields = if options.null_separated {
+                        fields
+                    } else {
+                        fields.iter().map(|f| escape_tsv(f)).collect()
+                    };
+                    write_fields(out, fields.iter().map(|f| f.as_str()), options)?;
+                }
+            }
+        }
+        _ => unreachable!("format is not supported in batch mode"),
+    }
+    Ok(())
+}
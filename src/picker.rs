@@ -0,0 +1,385 @@
+use crate::common::{AddrCollection, Entry, SortOrder};
+use crate::output::OutputOptions;
+use crate::template::Template;
+use crate::Matcher;
+use crossbeam_channel::{select, Receiver};
+use std::fs::File;
+use std::io::{Read, Write};
+use std::os::unix::io::AsRawFd;
+use std::time::Duration;
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+enum Key {
+    Char(char),
+    Ctrl(char),
+    Backspace,
+    Enter,
+    Tab,
+    Esc,
+    Up,
+    Down,
+    PageUp,
+    PageDown,
+}
+
+fn parse_keys(bytes: &[u8], keys: &mut Vec<Key>) {
+    let mut rest = bytes;
+    while let Some(&b) = rest.first() {
+        let (key, len) = match rest {
+            [0x1b] => (Some(Key::Esc), 1),
+            [0x1b, b'[', b'A', ..] | [0x1b, b'O', b'A', ..] => (Some(Key::Up), 3),
+            [0x1b, b'[', b'B', ..] | [0x1b, b'O', b'B', ..] => (Some(Key::Down), 3),
+            [0x1b, b'[', b'5', b'~', ..] => (Some(Key::PageUp), 4),
+            [0x1b, b'[', b'6', b'~', ..] => (Some(Key::PageDown), 4),
+            [0x1b, b'[', ..] => {
+                // Skip unknown CSI sequences (up to and including the final byte).
+                let end = rest[2..]
+                    .iter()
+                    .position(|b| (0x40..=0x7e).contains(b))
+                    .map(|p| p + 3)
+                    .unwrap_or(rest.len());
+                (None, end)
+            }
+            [0x1b, ..] => (Some(Key::Esc), 1),
+            [b'\r', ..] | [b'\n', ..] => (Some(Key::Enter), 1),
+            [b'\t', ..] => (Some(Key::Tab), 1),
+            [0x7f, ..] | [0x08, ..] => (Some(Key::Backspace), 1),
+            [c @ 1..=26, ..] => (Some(Key::Ctrl((b'a' + c - 1) as char)), 1),
+            _ => {
+                let len = match b {
+                    0xc0..=0xdf => 2,
+                    0xe0..=0xef => 3,
+                    0xf0..=0xf7 => 4,
+                    _ => 1,
+                }
+                .min(rest.len());
+                let key = std::str::from_utf8(&rest[..len])
+                    .ok()
+                    .and_then(|s| s.chars().next())
+                    .filter(|c| !c.is_control())
+                    .map(Key::Char);
+                (key, len)
+            }
+        };
+        keys.extend(key);
+        rest = &rest[len..];
+    }
+}
+
+/// Puts the controlling terminal into raw mode and switches to the alternate screen until
+/// dropped. The terminal is used directly (instead of stdin/stdout) so that the selection can be
+/// piped into other programs.
+struct Terminal {
+    tty: File,
+    original: libc::termios,
+}
+
+impl Terminal {
+    fn open() -> std::io::Result<Self> {
+        let tty = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open("/dev/tty")?;
+        // Safety: termios is a plain C struct that is filled in by tcgetattr.
+        let mut original = unsafe { std::mem::zeroed() };
+        if unsafe { libc::tcgetattr(tty.as_raw_fd(), &mut original) } != 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        let mut raw = original;
+        // Safety: raw is a valid termios struct obtained from tcgetattr.
+        unsafe { libc::cfmakeraw(&mut raw) };
+        if unsafe { libc::tcsetattr(tty.as_raw_fd(), libc::TCSANOW, &raw) } != 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        let mut terminal = Terminal { tty, original };
+        write!(terminal.tty, "\x1b[?1049h")?;
+        Ok(terminal)
+    }
+
+    /// (columns, rows)
+    fn size(&self) -> (usize, usize) {
+        // Safety: winsize is a plain C struct that is filled in by the ioctl.
+        let mut size: libc::winsize = unsafe { std::mem::zeroed() };
+        if unsafe { libc::ioctl(self.tty.as_raw_fd(), libc::TIOCGWINSZ, &mut size) } != 0
+            || size.ws_col == 0
+        {
+            return (80, 24);
+        }
+        (size.ws_col as usize, size.ws_row as usize)
+    }
+}
+
+impl Drop for Terminal {
+    fn drop(&mut self) {
+        let _ = write!(self.tty, "\x1b[?1049l");
+        let _ = self.tty.flush();
+        // Safety: original is the valid termios struct obtained in `open`.
+        unsafe { libc::tcsetattr(self.tty.as_raw_fd(), libc::TCSANOW, &self.original) };
+    }
+}
+
+fn truncate(s: &str, width: usize) -> String {
+    s.chars()
+        .map(|c| if c.is_control() { ' ' } else { c })
+        .take(width)
+        .collect()
+}
+
+struct Picker<'a> {
+    query: String,
+    cursor: usize,
+    scroll: usize,
+    /// Selected addresses, in the order they were selected.
+    marked: Vec<String>,
+    scanning: bool,
+    options: &'a OutputOptions,
+    line_template: Template,
+}
+
+impl<'a> Picker<'a> {
+    fn entries<'c, M: Matcher>(&self, collection: &'c AddrCollection) -> Vec<Entry<'c>> {
+        let mut options = self.options.clone();
+        if !self.query.is_empty() {
+            options.sort = SortOrder::Score;
+        }
+        collection.entries(&M::new(self.query.clone()), &options)
+    }
+
+    fn draw(
+        &mut self,
+        terminal: &mut Terminal,
+        entries: &[Entry],
+        collection: &AddrCollection,
+    ) -> std::io::Result<()> {
+        let (width, height) = terminal.size();
+        let list_height = height.saturating_sub(2).max(1);
+        self.cursor = self.cursor.min(entries.len().saturating_sub(1));
+        if self.cursor < self.scroll {
+            self.scroll = self.cursor;
+        } else if self.cursor >= self.scroll + list_height {
+            self.scroll = self.cursor + 1 - list_height;
+        }
+
+        let mut screen = Vec::new();
+        write!(screen, "\x1b[H\x1b[2J")?;
+        write!(
+            screen,
+            "\x1b[2m  {}/{} ({} mails{}{})\x1b[0m\r\n",
+            entries.len(),
+            collection.len(),
+            collection.num_mails(),
+            if self.scanning { ", scanning" } else { "" },
+            if self.marked.is_empty() {
+                String::new()
+            } else {
+                format!(", {} selected", self.marked.len())
+            }
+        )?;
+        for (i, entry) in entries
+            .iter()
+            .enumerate()
+            .skip(self.scroll)
+            .take(list_height)
+        {
+            let marker = if self.marked.iter().any(|m| m == entry.addr) {
+                '*'
+            } else {
+                ' '
+            };
+            let line = format!(
+                "{}{} {}",
+                if i == self.cursor { '>' } else { ' ' },
+                marker,
+                self.line_template.render(entry)
+            );
+            let line = truncate(&line, width);
+            if i == self.cursor {
+                write!(screen, "\x1b[7m{}\x1b[0m\r\n", line)?;
+            } else {
+                write!(screen, "{}\r\n", line)?;
+            }
+        }
+        // The prompt goes last so that the terminal cursor ends up behind the query.
+        write!(
+            screen,
+            "\x1b[{};1H> {}",
+            height,
+            truncate(&self.query, width.saturating_sub(2))
+        )?;
+        terminal.tty.write_all(&screen)?;
+        terminal.tty.flush()
+    }
+}
+
+enum Action {
+    Continue,
+    Accept,
+    Abort,
+}
+
+fn handle_key(picker: &mut Picker, key: Key, entries: &[Entry]) -> Action {
+    match key {
+        Key::Char(c) => {
+            picker.query.push(c);
+            picker.cursor = 0;
+        }
+        Key::Backspace => {
+            picker.query.pop();
+            picker.cursor = 0;
+        }
+        Key::Ctrl('u') => {
+            picker.query.clear();
+            picker.cursor = 0;
+        }
+        Key::Ctrl('w') => {
+            let trimmed = picker.query.trim_end().len();
+            picker.query.truncate(trimmed);
+            let word_start = picker.query.rfind(' ').map(|i| i + 1).unwrap_or(0);
+            picker.query.truncate(word_start);
+            picker.cursor = 0;
+        }
+        Key::Up | Key::Ctrl('p') | Key::Ctrl('k') => {
+            picker.cursor = picker.cursor.saturating_sub(1);
+        }
+        Key::Down | Key::Ctrl('n') => {
+            picker.cursor += 1;
+        }
+        Key::PageUp => picker.cursor = picker.cursor.saturating_sub(10),
+        Key::PageDown => picker.cursor += 10,
+        Key::Tab => {
+            if let Some(entry) = entries.get(picker.cursor) {
+                match picker.marked.iter().position(|m| m == entry.addr) {
+                    Some(i) => {
+                        picker.marked.remove(i);
+                    }
+                    None => picker.marked.push(entry.addr.to_owned()),
+                }
+                picker.cursor += 1;
+            }
+        }
+        Key::Enter => {
+            if picker.marked.is_empty() {
+                match entries.get(picker.cursor) {
+                    Some(entry) => picker.marked.push(entry.addr.to_owned()),
+                    None => return Action::Continue,
+                }
+            }
+            return Action::Accept;
+        }
+        Key::Esc | Key::Ctrl('c') | Key::Ctrl('g') => return Action::Abort,
+        Key::Ctrl(_) => {}
+    }
+    Action::Continue
+}
+
+/// Let the user interactively select addresses from `collection` (which is extended with all
+/// collections received from `updates`, e.g. from a running scan). Returns the selected
+/// addresses rendered using the template from `options` (`{mailbox}` by default) or `None` if
+/// the selection was aborted. Fails with the error of the scan if it receives one.
+/// How often the entries are updated with the addresses found while scanning.
+const REDRAW_INTERVAL: Duration = Duration::from_millis(100);
+
+pub fn pick<M: Matcher>(
+    mut collection: AddrCollection,
+    updates: Receiver<Result<AddrCollection, String>>,
+    query: String,
+    options: &OutputOptions,
+) -> std::io::Result<Option<Vec<String>>> {
+    let mut terminal = Terminal::open()?;
+
+    let (key_sender, key_receiver) = crossbeam_channel::unbounded();
+    let mut input = terminal.tty.try_clone()?;
+    std::thread::spawn(move || {
+        let mut buf = [0u8; 64];
+        let mut keys = Vec::new();
+        while let Ok(n) = input.read(&mut buf) {
+            if n == 0 {
+                break;
+            }
+            parse_keys(&buf[..n], &mut keys);
+            for key in keys.drain(..) {
+                if key_sender.send(key).is_err() {
+                    return;
+                }
+            }
+        }
+    });
+
+    let mut picker = Picker {
+        query,
+        cursor: 0,
+        scroll: 0,
+        marked: Vec::new(),
+        scanning: true,
+        options,
+        line_template: Template::parse("{mailbox} ({count})").unwrap(),
+    };
+    let mut updates = Some(updates);
+    // Updates that arrived since the last redraw. Filtering and sorting all entries is expensive,
+    // so they are only merged into the collection (at most) once per `REDRAW_INTERVAL`.
+    let mut pending: Option<AddrCollection> = None;
+
+    'pick: loop {
+        let entries = picker.entries::<M>(&collection);
+        picker.draw(&mut terminal, &entries, &collection)?;
+        let redraw = crossbeam_channel::after(REDRAW_INTERVAL);
+
+        loop {
+            let no_updates = crossbeam_channel::never();
+            let update_receiver = updates.as_ref().unwrap_or(&no_updates);
+            let no_redraw = crossbeam_channel::never();
+            let redraw_receiver = if pending.is_some() {
+                &redraw
+            } else {
+                &no_redraw
+            };
+            select! {
+                recv(key_receiver) -> key => {
+                    let key = match key {
+                        Ok(key) => key,
+                        Err(_) => return Ok(None),
+                    };
+                    match handle_key(&mut picker, key, &entries) {
+                        Action::Continue => break,
+                        Action::Abort => return Ok(None),
+                        Action::Accept => break 'pick,
+                    }
+                }
+                recv(update_receiver) -> update => {
+                    match update {
+                        Ok(Ok(update)) => match &mut pending {
+                            Some(pending) => pending.merge(update),
+                            None => pending = Some(update),
+                        },
+                        // The terminal is restored on return, before the caller reports the error.
+                        Ok(Err(e)) => return Err(std::io::Error::other(e)),
+                        Err(_) => {
+                            updates = None;
+                            picker.scanning = false;
+                            break;
+                        }
+                    }
+                }
+                recv(redraw_receiver) -> _ => break,
+            }
+        }
+
+        std::mem::drop(entries);
+        if let Some(update) = pending.take() {
+            collection.merge(update);
+        }
+    }
+    std::mem::drop(terminal);
+
+    let output_template = options
+        .template
+        .clone()
+        .unwrap_or_else(|| Template::parse("{mailbox}").unwrap());
+    let selection = picker
+        .marked
+        .iter()
+        .filter_map(|addr| collection.get(addr))
+        .map(|entry| output_template.render(&entry))
+        .collect();
+    Ok(Some(selection))
+}
@@ -0,0 +1,149 @@
+use crate::common::{AddrCollection, AddrData};
+use rustc_hash::FxHashMap;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Write};
+use std::path::Path;
+use std::sync::Arc;
+
+const FORMAT_NAME: &str = "maq-collection";
+const FORMAT_VERSION: u32 = 1;
+
+#[derive(Debug)]
+pub enum StoreError {
+    Io(std::io::Error),
+    Format(serde_json::Error),
+    NotACollection,
+    UnsupportedVersion(u32),
+}
+
+impl std::fmt::Display for StoreError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            StoreError::Io(e) => write!(f, "{}", e),
+            StoreError::Format(e) => write!(f, "Invalid collection file: {}", e),
+            StoreError::NotACollection => write!(f, "Not a maq collection file"),
+            StoreError::UnsupportedVersion(v) => write!(
+                f,
+                "Unsupported collection file version {} (maximum supported: {})",
+                v, FORMAT_VERSION
+            ),
+        }
+    }
+}
+
+impl std::error::Error for StoreError {}
+
+impl From<std::io::Error> for StoreError {
+    fn from(e: std::io::Error) -> Self {
+        StoreError::Io(e)
+    }
+}
+
+impl From<serde_json::Error> for StoreError {
+    fn from(e: serde_json::Error) -> Self {
+        StoreError::Format(e)
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct StoredCollection {
+    format: String,
+    version: u32,
+    #[serde(default)]
+    mails: u64,
+    addrs: Vec<StoredAddr>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct StoredAddr {
+    addr: String,
+    count: u64,
+    #[serde(default)]
+    names: BTreeMap<String, u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    first_seen: Option<i64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    last_seen: Option<i64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    folder: Option<String>,
+}
+
+impl From<&AddrCollection> for StoredCollection {
+    fn from(collection: &AddrCollection) -> Self {
+        let mut addrs = collection
+            .iter()
+            .map(|entry| StoredAddr {
+                addr: entry.addr.to_owned(),
+                count: entry.data.occurences,
+                names: entry
+                    .sorted_name_variants()
+                    .into_iter()
+                    .map(|(name, n)| (name.to_owned(), n))
+                    .collect(),
+                first_seen: entry.data.first_seen,
+                last_seen: entry.data.last_seen,
+                folder: entry.data.last_folder.as_deref().map(str::to_owned),
+            })
+            .collect::<Vec<_>>();
+        // Keep the output stable so that collection files can be diffed and versioned.
+        addrs.sort_by(|a, b| a.addr.cmp(&b.addr));
+
+        StoredCollection {
+            format: FORMAT_NAME.to_owned(),
+            version: FORMAT_VERSION,
+            mails: collection.num_mails(),
+            addrs,
+        }
+    }
+}
+
+impl From<StoredCollection> for AddrCollection {
+    fn from(stored: StoredCollection) -> Self {
+        let mut collection = AddrCollection::new();
+        collection.count_mails(stored.mails);
+        // There are far fewer folders than addresses.
+        let mut folders = FxHashMap::<String, Arc<str>>::default();
+        for addr in stored.addrs {
+            let folder = addr.folder.map(|folder| {
+                let interned = folders
+                    .entry(folder)
+                    .or_insert_with_key(|f| f.as_str().into());
+                interned.clone()
+            });
+            collection.add_data(
+                &addr.addr.to_lowercase(),
+                addr.names.iter().map(|(name, n)| (name.as_str(), *n)),
+                AddrData {
+                    name_variants: Vec::new(),
+                    occurences: addr.count,
+                    first_seen: addr.first_seen,
+                    last_seen: addr.last_seen,
+                    last_folder: folder,
+                },
+            );
+        }
+        collection
+    }
+}
+
+pub fn save(collection: &AddrCollection, path: &Path) -> Result<(), StoreError> {
+    let mut writer = BufWriter::new(File::create(path)?);
+    serde_json::to_writer(&mut writer, &StoredCollection::from(collection))?;
+    writeln!(writer)?;
+    writer.flush()?;
+    Ok(())
+}
+
+pub fn load(path: &Path) -> Result<AddrCollection, StoreError> {
+    let reader = BufReader::new(File::open(path)?);
+    let stored: StoredCollection = serde_json::from_reader(reader)?;
+    if stored.format != FORMAT_NAME {
+        return Err(StoreError::NotACollection);
+    }
+    if stored.version > FORMAT_VERSION {
+        return Err(StoreError::UnsupportedVersion(stored.version));
+    }
+    Ok(stored.into())
+}
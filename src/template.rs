@@ -0,0 +1,201 @@
+use crate::common::Entry;
+use crate::output::{format_date, format_datetime, quote_display_name};
+
+/// A line template such as `{name|quote} <{addr}>` that is rendered once per entry.
+///
+/// Placeholders are written as `{field}` or `{field|filter|...}`, literal braces as `{{` and `}}`
+/// and `\t`, `\n` and `\\` are replaced by tab, newline and backslash.
+#[derive(Clone, Debug)]
+pub struct Template {
+    segments: Vec<Segment>,
+}
+
+#[derive(Clone, Debug)]
+enum Segment {
+    Literal(String),
+    Placeholder(Field, Vec<Filter>),
+}
+
+#[derive(Copy, Clone, Debug)]
+enum Field {
+    Addr,
+    Name,
+    Names,
+    Mailbox,
+    Count,
+    FirstSeen,
+    LastSeen,
+    Folder,
+    Score,
+}
+
+impl Field {
+    fn parse(s: &str) -> Result<Self, String> {
+        Ok(match s {
+            "addr" => Field::Addr,
+            "name" => Field::Name,
+            "names" => Field::Names,
+            "mailbox" => Field::Mailbox,
+            "count" => Field::Count,
+            "first_seen" => Field::FirstSeen,
+            "last_seen" => Field::LastSeen,
+            "folder" => Field::Folder,
+            "score" => Field::Score,
+            _ => return Err(format!("Unknown template field: {}", s)),
+        })
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
+enum Filter {
+    /// Quote as an RFC 5322 display name (if necessary).
+    Quote,
+    /// Format a time as date only (YYYY-MM-DD).
+    Date,
+    /// Format a time as seconds since the epoch.
+    Unix,
+    /// Encode as a JSON string.
+    Json,
+    Lower,
+    Upper,
+}
+
+impl Filter {
+    fn parse(s: &str) -> Result<Self, String> {
+        Ok(match s {
+            "quote" => Filter::Quote,
+            "date" => Filter::Date,
+            "unix" => Filter::Unix,
+            "json" => Filter::Json,
+            "lower" => Filter::Lower,
+            "upper" => Filter::Upper,
+            _ => return Err(format!("Unknown template filter: {}", s)),
+        })
+    }
+}
+
+enum Value {
+    Text(String),
+    Time(Option<i64>),
+}
+
+impl Value {
+    fn into_text(self) -> String {
+        match self {
+            Value::Text(s) => s,
+            Value::Time(t) => t.map(format_datetime).unwrap_or_default(),
+        }
+    }
+
+    fn apply(self, filter: Filter) -> Value {
+        match (filter, self) {
+            (Filter::Date, Value::Time(t)) => Value::Text(t.map(format_date).unwrap_or_default()),
+            (Filter::Unix, Value::Time(t)) => {
+                Value::Text(t.map(|t| t.to_string()).unwrap_or_default())
+            }
+            (Filter::Date, v) | (Filter::Unix, v) => v,
+            (Filter::Quote, v) => Value::Text(quote_display_name(&v.into_text())),
+            (Filter::Json, v) => Value::Text(serde_json::to_string(&v.into_text()).unwrap()),
+            (Filter::Lower, v) => Value::Text(v.into_text().to_lowercase()),
+            (Filter::Upper, v) => Value::Text(v.into_text().to_uppercase()),
+        }
+    }
+}
+
+fn field_value(field: Field, entry: &Entry) -> Value {
+    let data = entry.data;
+    match field {
+        Field::Addr => Value::Text(entry.addr.to_owned()),
+        Field::Name => Value::Text(entry.preferred_name().unwrap_or("").to_owned()),
+        Field::Names => Value::Text(
+            entry
+                .sorted_name_variants()
+                .into_iter()
+                .map(|(name, _)| name)
+                .collect::<Vec<_>>()
+                .join("; "),
+        ),
+        Field::Mailbox => Value::Text(match entry.preferred_name() {
+            Some(name) => format!("{} <{}>", quote_display_name(name), entry.addr),
+            None => entry.addr.to_owned(),
+        }),
+        Field::Count => Value::Text(data.occurences.to_string()),
+        Field::FirstSeen => Value::Time(data.first_seen),
+        Field::LastSeen => Value::Time(data.last_seen),
+        Field::Folder => Value::Text(data.last_folder.as_deref().unwrap_or_default().to_owned()),
+        Field::Score => Value::Text(entry.score.to_string()),
+    }
+}
+
+impl Template {
+    pub fn parse(template: &str) -> Result<Self, String> {
+        let mut segments = Vec::new();
+        let mut literal = String::new();
+        let mut chars = template.chars().peekable();
+        while let Some(c) = chars.next() {
+            match c {
+                '{' if chars.peek() == Some(&'{') => {
+                    chars.next();
+                    literal.push('{');
+                }
+                '}' if chars.peek() == Some(&'}') => {
+                    chars.next();
+                    literal.push('}');
+                }
+                '}' => {
+                    return Err("Unmatched '}' in template (use '}}' for a literal brace)".into())
+                }
+                '\\' => match chars.next() {
+                    Some('t') => literal.push('\t'),
+                    Some('n') => literal.push('\n'),
+                    Some('\\') => literal.push('\\'),
+                    Some(c) => {
+                        literal.push('\\');
+                        literal.push(c);
+                    }
+                    None => literal.push('\\'),
+                },
+                '{' => {
+                    let mut placeholder = String::new();
+                    loop {
+                        match chars.next() {
+                            Some('}') => break,
+                            Some(c) => placeholder.push(c),
+                            None => return Err("Unclosed '{' in template".into()),
+                        }
+                    }
+                    let mut parts = placeholder.split('|').map(str::trim);
+                    let field = Field::parse(parts.next().unwrap_or(""))?;
+                    let filters = parts.map(Filter::parse).collect::<Result<Vec<_>, _>>()?;
+                    if !literal.is_empty() {
+                        segments.push(Segment::Literal(std::mem::take(&mut literal)));
+                    }
+                    segments.push(Segment::Placeholder(field, filters));
+                }
+                c => literal.push(c),
+            }
+        }
+        if !literal.is_empty() {
+            segments.push(Segment::Literal(literal));
+        }
+        Ok(Template { segments })
+    }
+
+    pub fn render(&self, entry: &Entry) -> String {
+        let mut out = String::new();
+        for segment in &self.segments {
+            match segment {
+                Segment::Literal(s) => out.push_str(s),
+                Segment::Placeholder(field, filters) => {
+                    let value = filters
+                        .iter()
+                        .fold(field_value(*field, entry), |value, filter| {
+                            value.apply(*filter)
+                        });
+                    out.push_str(&value.into_text());
+                }
+            }
+        }
+        out
+    }
+}
@@ -0,0 +1,231 @@
+//! Finding the most frequent addresses of huge archives in bounded memory (see `--top`).
+//!
+//! Every address is counted in a count-min sketch, which overestimates but never underestimates
+//! how often an address was seen. Only addresses whose estimate is among the highest ones are
+//! remembered. Their exact counts are determined by a second scan that ignores all other
+//! addresses.
+
+use rustc_hash::{FxHashMap, FxHashSet, FxHasher};
+use std::hash::Hasher;
+
+/// Number of rows of the sketch. Each address is counted once per row.
+const SKETCH_DEPTH: usize = 4;
+
+/// Every sketch has at least this many counters per row.
+const MIN_SKETCH_WIDTH: usize = 1024;
+
+/// How many more candidates than requested addresses are kept, to make up for the estimates.
+const CANDIDATES_PER_RESULT: usize = 4;
+
+#[derive(Copy, Clone, Debug)]
+pub struct TopK {
+    /// Number of addresses to find.
+    pub k: usize,
+    /// Memory for the counters of a single sketch.
+    pub sketch_bytes: usize,
+}
+
+impl TopK {
+    /// Number of candidates (at most) whose exact count is determined.
+    pub fn candidates(&self) -> usize {
+        self.k.saturating_mul(CANDIDATES_PER_RESULT)
+    }
+}
+
+/// Counters of approximate frequencies (see module documentation).
+struct CountMinSketch {
+    /// `SKETCH_DEPTH` rows of `width` counters each.
+    counters: Vec<u32>,
+    width: usize,
+}
+
+impl CountMinSketch {
+    fn new(bytes: usize) -> Self {
+        let width = (bytes / (SKETCH_DEPTH * std::mem::size_of::<u32>())).max(MIN_SKETCH_WIDTH);
+        CountMinSketch {
+            counters: vec![0; width * SKETCH_DEPTH],
+            width,
+        }
+    }
+
+    /// The counter of `key` in every row.
+    fn indices(&self, key: &str) -> [usize; SKETCH_DEPTH] {
+        let mut hasher = FxHasher::default();
+        hasher.write(key.as_bytes());
+        // FxHash spreads its low bits poorly, so they are mixed (with the finalizer of SplitMix64)
+        // before the rows are derived from the two halves of the hash.
+        let mut h = hasher.finish();
+        h = (h ^ (h >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        h = (h ^ (h >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        h ^= h >> 31;
+        let (a, b) = (h & 0xffff_ffff, (h >> 32) | 1);
+        let mut indices = [0; SKETCH_DEPTH];
+        for (row, index) in indices.iter_mut().enumerate() {
+            let column = a.wrapping_add(b.wrapping_mul(row as u64)) % self.width as u64;
+            *index = row * self.width + column as usize;
+        }
+        indices
+    }
+
+    /// Count `key` once and return its new estimate. Only the lowest counters are incremented
+    /// (conservative update), which keeps the estimates of other keys lower.
+    fn add(&mut self, key: &str) -> u32 {
+        let indices = self.indices(key);
+        let estimate = indices
+            .iter()
+            .map(|&i| self.counters[i])
+            .min()
+            .unwrap()
+            .saturating_add(1);
+        for &i in &indices {
+            self.counters[i] = self.counters[i].max(estimate);
+        }
+        estimate
+    }
+
+    fn estimate(&self, key: &str) -> u32 {
+        self.indices(key)
+            .iter()
+            .map(|&i| self.counters[i])
+            .min()
+            .unwrap()
+    }
+
+    /// Add the counts of `other`, which must have the same size.
+    fn merge(&mut self, other: &CountMinSketch) {
+        assert_eq!(self.width, other.width);
+        for (counter, other) in self.counters.iter_mut().zip(&other.counters) {
+            *counter = counter.saturating_add(*other);
+        }
+    }
+}
+
+/// The addresses with the highest estimated counts.
+pub struct HeavyHitters {
+    sketch: CountMinSketch,
+    /// Estimates of the candidates as of the last time they were seen.
+    candidates: FxHashMap<String, u32>,
+    capacity: usize,
+    /// Highest estimate of a candidate that was dropped. Addresses whose estimate is not higher
+    /// are not worth remembering.
+    threshold: u32,
+}
+
+impl HeavyHitters {
+    pub fn new(top: &TopK) -> Self {
+        HeavyHitters {
+            sketch: CountMinSketch::new(top.sketch_bytes),
+            candidates: FxHashMap::default(),
+            capacity: top.candidates(),
+            threshold: 0,
+        }
+    }
+
+    /// Count one occurence of `addr`.
+    pub fn add(&mut self, addr: &str) {
+        let estimate = self.sketch.add(addr);
+        if let Some(candidate) = self.candidates.get_mut(addr) {
+            *candidate = estimate;
+        } else if estimate > self.threshold {
+            self.candidates.insert(addr.to_owned(), estimate);
+            // Dropping candidates one at a time would mean looking for the lowest one for every
+            // new address, so twice as many are collected before half of them are dropped.
+            if self.candidates.len() > 2 * self.capacity {
+                self.shrink();
+            }
+        }
+    }
+
+    /// Keep only the `capacity` candidates with the highest estimates.
+    fn shrink(&mut self) {
+        if self.candidates.len() <= self.capacity {
+            return;
+        }
+        let mut candidates = self.candidates.drain().collect::<Vec<_>>();
+        // Ties are broken by address, so that the same candidates survive every time.
+        candidates.sort_unstable_by(|(a, a_estimate), (b, b_estimate)| {
+            b_estimate.cmp(a_estimate).then(a.cmp(b))
+        });
+        let dropped = candidates.split_off(self.capacity);
+        self.threshold = self.threshold.max(dropped[0].1);
+        self.candidates.extend(candidates);
+    }
+
+    /// Combine the counts of both, as if all addresses had been added to `self`.
+    pub fn merge(&mut self, other: HeavyHitters) {
+        self.sketch.merge(&other.sketch);
+        self.threshold = self.threshold.max(other.threshold);
+        self.candidates.extend(other.candidates);
+        // Each side only saw some of the occurences.
+        let sketch = &self.sketch;
+        for (addr, estimate) in &mut self.candidates {
+            *estimate = sketch.estimate(addr);
+        }
+        self.shrink();
+    }
+
+    /// The (lowercase) addresses that are most likely the most frequent ones.
+    pub fn into_candidates(mut self) -> FxHashSet<String> {
+        self.shrink();
+        self.candidates.into_keys().collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn merged_candidates_contain_the_top_addresses() {
+        let top = TopK {
+            k: 10,
+            sketch_bytes: 16 * 1024,
+        };
+        // Each instance only sees half of the occurences of the most frequent addresses, which
+        // are therefore less frequent than many addresses that only one of the instances sees.
+        // Only once merged, the instances know that the former are the most frequent ones.
+        let mut stream = Vec::new();
+        for i in 0..5 {
+            for j in 0..2000 {
+                stream.push((format!("top{}@example.org", i), j % 2));
+            }
+        }
+        for i in 0..68 {
+            for _ in 0..1900 - 9 * i {
+                stream.push((format!("one-sided{}@example.org", i), i % 2));
+            }
+        }
+        // And lots of rare addresses.
+        for i in 0..50_000 {
+            for j in 0..1 + i % 3 {
+                stream.push((format!("rare{}@example.org", i), (i + j) % 2));
+            }
+        }
+        // Shuffled with xorshift, so that failures are reproducible.
+        let mut state = 0x2545_f491_4f6c_dd1du64;
+        for i in (1..stream.len()).rev() {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            stream.swap(i, (state % (i as u64 + 1)) as usize);
+        }
+
+        let mut a = HeavyHitters::new(&top);
+        let mut b = HeavyHitters::new(&top);
+        for (addr, side) in &stream {
+            match side {
+                0 => a.add(addr),
+                _ => b.add(addr),
+            }
+        }
+        a.merge(b);
+        let candidates = a.into_candidates();
+        assert!(candidates.len() <= top.candidates());
+        let top_addrs = (0..5)
+            .map(|i| format!("top{}@example.org", i))
+            .chain((0..5).map(|i| format!("one-sided{}@example.org", i)));
+        for addr in top_addrs {
+            assert!(candidates.contains(&addr), "{} is missing", addr);
+        }
+    }
+}